@@ -11,9 +11,12 @@ pub struct User {
 
 /// User must implement the Document Traits
 impl Document<User> for User {
+    /// The type of the primary key
+    type Key = Uuid;
+
     /// How we derive the primary key for the document
     fn primary_key(&self) -> Uuid {
-        self.uuid.clone()
+        self.uuid
     }
     /// A fcn that needs to be satisfied to prevent any clashes
     /// Can contain as many checks as you like.
@@ -22,7 +25,7 @@ impl Document<User> for User {
         if self.name == doc.name {
             return Err("Name is already in use.");
         }
-        return Ok(());
+        Ok(())
     }
 }
 
@@ -42,7 +45,7 @@ fn main() {
     fp.push("users");
 
     // Create the collection and pass the dir.
-    let mut users = Collection::<User>::new(CollectionBackend::Dir, Some(fp));
+    let mut users = Collection::<User>::new(CollectionBackend::Dir, Some(fp)).unwrap();
 
     let user = User::new("demo".to_string());
     println!("{:?}", user);
@@ -6,6 +6,7 @@ use argon2::{
 };
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use struvedb::{Collection, CollectionBackend, Document};
 use uuid::Uuid;
 
@@ -34,15 +35,18 @@ pub struct User {
 /// And different checking criteria (e.g., unique keys)
 /// for all our documents.
 impl Document<User> for User {
+    // Email uniqueness is covered by unique_keys
+    // so there is no need to scan every user.
+    const CHECK_INTERSECTS: bool = false;
+
+    type Key = Uuid;
+
     fn primary_key(&self) -> Uuid {
-        self.uuid.clone()
+        self.uuid
     }
 
-    fn intersects(&self, doc: &User) -> Result<(), &str> {
-        if self.email == doc.email {
-            return Err("Email is already in use.");
-        }
-        return Ok(());
+    fn unique_keys(&self) -> Vec<(&'static str, Value)> {
+        vec![("email", json!(self.email))]
     }
 }
 
@@ -75,7 +79,7 @@ fn main() {
     fp.push("users");
 
     // Create the collection
-    let mut user_collection = Collection::<User>::new(CollectionBackend::Dir, Some(fp));
+    let mut user_collection = Collection::<User>::new(CollectionBackend::Dir, Some(fp)).unwrap();
 
     let user = User::new(
         "example".to_string(),
@@ -0,0 +1,73 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use crate::{Document, DocumentMeta, Durability, Error};
+
+/// The outcome of compacting a backend.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionStats {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Number of documents that now live in a different row.
+    pub rows_moved: usize,
+}
+
+/// Storage used by a collection to persist its documents.
+///
+/// The collection keeps every document in memory and calls
+/// the backend once a write has passed its checks. Implement this
+/// trait to persist documents somewhere other than the
+/// built-in backends.
+pub trait StorageBackend<T: Document<T>>: Send + Sync {
+    /// Read every persisted document with its metadata.
+    /// Called once when the collection is created.
+    fn load_all(&mut self) -> Result<Vec<(T, DocumentMeta)>, Error>;
+    /// Persist a document that is new to the collection.
+    /// The metadata should be stored with it and returned by `load_all`.
+    fn persist_insert(&mut self, doc: &T, meta: &DocumentMeta) -> Result<(), Error>;
+    /// Persist a new version of a document. The document should be
+    /// inserted if the backend does not hold it, as writes replayed from
+    /// the write-ahead log are applied as updates.
+    fn persist_update(&mut self, doc: &T, meta: &DocumentMeta) -> Result<(), Error>;
+    /// Remove a document from the store.
+    fn persist_delete(&mut self, pk: &T::Key) -> Result<(), Error>;
+    /// Flush any buffered writes to the store and sync them to disk.
+    /// How often this is called is governed by the collection's `Durability`.
+    fn flush(&mut self) -> Result<(), Error>;
+    /// Told the collection's `Durability` when it is opened or changed.
+    /// Backends that sync as part of a write use it to decide whether to.
+    fn set_durability(&mut self, _durability: Durability) {}
+    /// Reclaim space left behind by deletes and resizes.
+    /// Backends with nothing to reclaim keep the default.
+    fn compact(&mut self) -> Result<CompactionStats, Error> {
+        Ok(CompactionStats::default())
+    }
+    /// Where `Collection::enable_history` keeps the history of the
+    /// documents. Backends that do not persist it keep the default
+    /// and the history is kept in memory.
+    fn history_path(&self) -> Option<PathBuf> {
+        None
+    }
+    /// Read the key generator counter saved by `persist_counter`.
+    /// Backends that do not persist it keep the default.
+    fn load_counter(&mut self) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+    /// Save the key generator counter with the collection.
+    fn persist_counter(&mut self, _counter: u64) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Sync the directory containing `path` so that a
+/// rename or new file within it survives a crash.
+pub(crate) fn sync_dir(path: &Path) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
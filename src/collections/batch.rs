@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Document, Error};
+
+use super::collection::Collection;
+use super::index::unique_violation;
+use super::wal::WalOp;
+
+/// Inserts, updates and deletes staged against a collection that
+/// are committed together. Either every write succeeds or none do.
+pub struct Batch<'a, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    collection: &'a mut Collection<T>,
+    ops: Vec<WalOp<T>>,
+}
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Start a batch of writes that succeed only if they all succeed.
+    pub fn batch(&mut self) -> Batch<'_, T> {
+        Batch {
+            collection: self,
+            ops: vec![],
+        }
+    }
+}
+
+impl<'a, T> Batch<'a, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    pub fn insert(mut self, doc: T) -> Self {
+        self.ops.push(WalOp::Insert(doc));
+        self
+    }
+
+    pub fn update(mut self, doc: T) -> Self {
+        self.ops.push(WalOp::Update(doc));
+        self
+    }
+
+    pub fn delete(mut self, pk: &T::Key) -> Self {
+        self.ops.push(WalOp::Delete(pk.clone()));
+        self
+    }
+
+    /// Run the before hooks on every staged write and check each one
+    /// against the collection and the writes staged before it, then
+    /// apply them all. Nothing is written if a check fails, and the
+    /// writes are rolled back if the backend fails.
+    pub fn commit(self) -> Result<(), Error> {
+        let Batch { collection, ops } = self;
+        let ops = ops
+            .into_iter()
+            .map(|op| match op {
+                WalOp::Delete(pk) => collection.delete_op(pk),
+                op => op,
+            })
+            .map(|op| collection.run_before_hooks(op))
+            .collect::<Result<Vec<_>, _>>()?;
+        for op in ops.iter() {
+            if let WalOp::Insert(doc) | WalOp::Update(doc) = op {
+                collection.sweep_conflicts(doc)?;
+            }
+        }
+        let documents = &collection.documents;
+        let now = Utc::now();
+        let unique_index = &collection.unique_index;
+        // Documents as they will be once the writes so far are applied.
+        // `None` marks a staged delete.
+        let mut staged: HashMap<T::Key, Option<&T>> = HashMap::new();
+        // Unique keys taken or released by the writes so far.
+        let mut staged_keys: HashMap<(&'static str, String), Option<T::Key>> = HashMap::new();
+        for op in ops.iter() {
+            let pk = match op {
+                WalOp::Insert(doc) | WalOp::Update(doc) => doc.primary_key(),
+                WalOp::Delete(pk) | WalOp::SoftDelete(pk) => pk.clone(),
+            };
+            let current = match staged.get(&pk) {
+                Some(doc) => *doc,
+                None => documents.get(&pk),
+            };
+            if let Some(current) = current {
+                for (name, value) in current.unique_keys() {
+                    staged_keys.insert((name, value.to_string()), None);
+                }
+            }
+            match op {
+                WalOp::Insert(doc) | WalOp::Update(doc) => {
+                    collection.validate(doc)?;
+                    if matches!(op, WalOp::Insert(_)) && current.is_some() {
+                        return Err(Error::DuplicateKey);
+                    }
+                    for (name, value) in doc.unique_keys() {
+                        let key = (name, value.to_string());
+                        let owner = match staged_keys.get(&key) {
+                            Some(owner) => owner.clone(),
+                            None => unique_index.owner(name, &value).cloned(),
+                        };
+                        if owner.is_some_and(|owner| owner != pk) {
+                            return Err(unique_violation(name, &value));
+                        }
+                        staged_keys.insert(key, Some(pk.clone()));
+                    }
+                    let others = collection
+                        .unexpired(now)
+                        .filter(|doc| !staged.contains_key(&doc.primary_key()))
+                        .chain(staged.values().flatten().copied());
+                    Collection::check_intersects(doc, others)?;
+                    staged.insert(pk, Some(doc));
+                }
+                WalOp::Delete(_) | WalOp::SoftDelete(_) => {
+                    let hidden = !staged.contains_key(&pk) && !collection.is_visible(&pk);
+                    let Some(current) = current.filter(|_| !hidden) else {
+                        return Err(Error::NotFound);
+                    };
+                    if matches!(op, WalOp::Delete(_)) {
+                        staged.insert(pk, None);
+                        continue;
+                    }
+                    // Soft deleted documents are kept along with their unique keys.
+                    for (name, value) in current.unique_keys() {
+                        staged_keys.insert((name, value.to_string()), Some(pk.clone()));
+                    }
+                    staged.insert(pk, Some(current));
+                }
+            }
+        }
+
+        collection.commit(ops)
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt::Debug;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Document, DocumentMeta};
+
+use super::collection::Collection;
+
+/// A write made to a collection, sent to subscribers once it has been applied.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent<T> {
+    Inserted { new: T },
+    Updated { old: T, new: T },
+    Deleted { old: T },
+}
+
+impl<T> ChangeEvent<T> {
+    /// The document as it is after the write, or before it for a delete.
+    pub fn document(&self) -> &T {
+        match self {
+            ChangeEvent::Inserted { new } | ChangeEvent::Updated { new, .. } => new,
+            ChangeEvent::Deleted { old } => old,
+        }
+    }
+}
+
+/// A write applied to the collection, with the metadata of the
+/// version it replaced. `new` is only kept when it is needed
+/// for subscribers or hooks.
+pub(super) struct Change<T> {
+    pub old: Option<(T, DocumentMeta)>,
+    pub new: Option<T>,
+    pub deleted: bool,
+}
+
+impl<T> Change<T> {
+    /// The event for the change. Purging a soft deleted document is
+    /// silent and undeleting one is reported as an insert.
+    pub fn event(self) -> Option<ChangeEvent<T>> {
+        let soft_deleted = matches!(self.old, Some((_, meta)) if meta.deleted_at.is_some());
+        match (self.old, self.new) {
+            (Some(_), None) if soft_deleted => None,
+            (Some((old, _)), _) if self.deleted => Some(ChangeEvent::Deleted { old }),
+            (Some(_), Some(new)) if soft_deleted => Some(ChangeEvent::Inserted { new }),
+            (Some((old, _)), Some(new)) => Some(ChangeEvent::Updated { old, new }),
+            (None, Some(new)) => Some(ChangeEvent::Inserted { new }),
+            _ => None,
+        }
+    }
+}
+
+type Filter<T> = Box<dyn Fn(&T) -> bool + Send + Sync>;
+
+pub(super) struct Subscriber<T> {
+    sender: Sender<ChangeEvent<T>>,
+    filter: Option<Filter<T>>,
+}
+
+impl<T> Subscriber<T> {
+    /// Whether the subscriber wants to hear about the event. An update is
+    /// sent if either version matches so subscribers see documents leave
+    /// the set they filter for.
+    fn wants(&self, event: &ChangeEvent<T>) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+        match event {
+            ChangeEvent::Inserted { new } => filter(new),
+            ChangeEvent::Updated { old, new } => filter(old) || filter(new),
+            ChangeEvent::Deleted { old } => filter(old),
+        }
+    }
+}
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Receive a `ChangeEvent` for every insert, update and delete
+    /// from now on. Events are sent after the write succeeds and a
+    /// subscriber is removed when its receiver is dropped.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent<T>> {
+        self.add_subscriber(None)
+    }
+
+    /// Like `subscribe`, but only for writes to documents that satisfy `f`,
+    /// either before or after the write.
+    pub fn subscribe_filtered(
+        &mut self,
+        f: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> Receiver<ChangeEvent<T>> {
+        self.add_subscriber(Some(Box::new(f)))
+    }
+
+    fn add_subscriber(&mut self, filter: Option<Filter<T>>) -> Receiver<ChangeEvent<T>> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(Subscriber { sender, filter });
+        receiver
+    }
+
+    /// Send events to the subscribers that want them.
+    pub(super) fn publish(&mut self, events: Vec<ChangeEvent<T>>) {
+        for event in events {
+            self.subscribers.retain(|subscriber| {
+                !subscriber.wants(&event) || subscriber.sender.send(event.clone()).is_ok()
+            });
+        }
+    }
+}
@@ -1,195 +1,615 @@
 use std::{
+    collections::HashMap,
     fmt::Debug,
-    fs::File,
     path::PathBuf,
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 
+use chrono::{TimeDelta, Utc};
 use indexmap::IndexMap;
-use serde::{de::DeserializeOwned, Serialize};
-use uuid::Uuid;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::Document;
+use crate::{
+    CompactionStats, DirBackend, Document, DocumentMeta, Error, FileBackend, InMemoryBackend,
+    StorageBackend, Validate, ValidationErrors,
+};
+
+use super::changes::{Change, ChangeEvent, Subscriber};
+use super::history::History;
+use super::hooks::Hooks;
+use super::index::{SecondaryIndex, UniqueIndex};
+use super::keys::KeyGenerator;
+use super::wal::{Wal, WalOp};
 
+/// The built-in storage backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CollectionBackend {
     InMemory,
     Dir,
     File,
 }
 
+/// When the backend syncs writes to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Never sync. Writes are left to the operating system to persist
+    /// and a crash can lose or truncate them.
+    None,
+    /// Sync after every insert, update and delete.
+    #[default]
+    FlushOnEachWrite,
+    /// Sync at most once per `interval`, on the first write after it has
+    /// elapsed, and when the collection is dropped. Writes followed by
+    /// idle time stay unsynced until then or until `Collection::sync`.
+    GroupCommit { interval: Duration },
+    /// Only sync when `Collection::sync` is called.
+    Manual,
+}
+
+/// Settings for a collection created with `Collection::with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionOptions {
+    /// Record every write in a write-ahead log at this path before it is
+    /// applied to the backend. Writes left in the log by a crash are
+    /// replayed when the collection is next opened.
+    pub wal_path: Option<PathBuf>,
+    pub durability: Durability,
+}
+
+type Validator<T> = fn(&T) -> Result<(), ValidationErrors>;
+
 pub struct Collection<T: Document<T> + Debug + Serialize + DeserializeOwned + Clone + Sync + Send> {
-    pub path: Option<PathBuf>,
-    pub documents: IndexMap<Uuid, T>,
-    pub backend: CollectionBackend,
-    pub max_byte_length: usize,
-    pub byte_length_increment: usize,
-    pub file: Option<File>,
+    pub(super) documents: IndexMap<T::Key, T>,
+    pub(super) meta: HashMap<T::Key, DocumentMeta>,
+    pub(super) backend: Box<dyn StorageBackend<T>>,
+    pub(super) unique_index: UniqueIndex<T::Key>,
+    pub(super) indexes: HashMap<String, Box<dyn SecondaryIndex<T>>>,
+    pub(super) wal: Option<Wal>,
+    durability: Durability,
+    pub(super) key_generator: Option<Box<dyn KeyGenerator<T::Key>>>,
+    pub(super) subscribers: Vec<Subscriber<T>>,
+    pub(super) hooks: Hooks<T>,
+    validator: Option<Validator<T>>,
+    pub(super) history: Option<History<T>>,
+    /// The last revision of each deleted document when there is no history
+    /// to look it up in, so a document written again under the same key
+    /// carries on from it.
+    deleted_revisions: HashMap<T::Key, u64>,
+    pub(super) soft_delete: bool,
+    pub(super) ttl: Option<TimeDelta>,
+    last_sync: Instant,
 }
 
 impl<T> Collection<T>
 where
     T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
 {
-    pub fn new(backend: CollectionBackend, path: Option<PathBuf>) -> Self {
+    /// Create a collection using one of the built-in backends.
+    /// `path` is required for the `Dir` and `File` backends.
+    pub fn new(backend: CollectionBackend, path: Option<PathBuf>) -> Result<Self, Error> {
+        Collection::open(backend, path, CollectionOptions::default())
+    }
+
+    /// Create a collection using one of the built-in backends with the given options.
+    pub fn open(
+        backend: CollectionBackend,
+        path: Option<PathBuf>,
+        options: CollectionOptions,
+    ) -> Result<Self, Error> {
+        let backend: Box<dyn StorageBackend<T>> = match backend {
+            CollectionBackend::Dir => Box::new(DirBackend::new(path.ok_or(Error::MissingPath)?)?),
+            CollectionBackend::File => Box::new(FileBackend::new(path.ok_or(Error::MissingPath)?)?),
+            CollectionBackend::InMemory => Box::new(InMemoryBackend::new()),
+        };
+        Collection::with_options(backend, options)
+    }
+
+    /// Create a collection on top of any storage backend,
+    /// loading the documents it already holds.
+    pub fn with_backend(backend: Box<dyn StorageBackend<T>>) -> Result<Self, Error> {
+        Collection::with_options(backend, CollectionOptions::default())
+    }
+
+    /// Create a collection on top of any storage backend with the given options.
+    pub fn with_options(
+        mut backend: Box<dyn StorageBackend<T>>,
+        options: CollectionOptions,
+    ) -> Result<Self, Error> {
+        let docs = backend.load_all()?;
+        backend.set_durability(options.durability);
         let mut collection = Collection {
-            path,
-            documents: IndexMap::new(),
+            documents: IndexMap::with_capacity(docs.len()),
+            meta: HashMap::with_capacity(docs.len()),
             backend,
-            max_byte_length: 128,
-            byte_length_increment: 64,
-            file: None,
+            unique_index: UniqueIndex::default(),
+            indexes: HashMap::new(),
+            wal: None,
+            durability: options.durability,
+            key_generator: None,
+            subscribers: vec![],
+            hooks: Hooks::default(),
+            validator: None,
+            history: None,
+            deleted_revisions: HashMap::new(),
+            soft_delete: false,
+            ttl: None,
+            last_sync: Instant::now(),
         };
+        for (doc, meta) in docs {
+            collection.store(doc, meta);
+        }
 
-        match collection.backend {
-            CollectionBackend::Dir => collection.load_structs_from_dir(),
-            CollectionBackend::File => collection.load_structs_from_file(),
-            CollectionBackend::InMemory => {}
+        if let Some(wal_path) = options.wal_path {
+            let mut wal = Wal::open(wal_path)?;
+            for ops in wal.read_all::<T>()? {
+                for op in ops {
+                    collection.replay(op)?;
+                }
+            }
+            collection.backend.flush()?;
+            wal.checkpoint()?;
+            collection.wal = Some(wal);
         }
 
-        collection
+        Ok(collection)
     }
 
     pub fn new_arc(
         backend: CollectionBackend,
         path: Option<PathBuf>,
-    ) -> Arc<RwLock<Collection<T>>> {
-        let c = Collection::new(backend, path);
-        return Arc::new(RwLock::new(c));
+    ) -> Result<Arc<RwLock<Collection<T>>>, Error> {
+        let c = Collection::new(backend, path)?;
+        Ok(Arc::new(RwLock::new(c)))
     }
 
-    pub fn insert(&mut self, new_doc: T) -> Result<(), &str> {
+    pub fn insert(&mut self, new_doc: T) -> Result<(), Error> {
+        let new_doc = self.before_write(new_doc, true)?;
+        self.sweep_conflicts(&new_doc)?;
+        self.validate(&new_doc)?;
         if self.documents.contains_key(&new_doc.primary_key()) {
-            return Err("Primary key used");
+            return Err(Error::DuplicateKey);
         }
 
-        for (_, doc) in self.documents.iter() {
-            // No clash on self as you may be updating it.
-            if new_doc.primary_key() != doc.primary_key() {
-                let ans = new_doc.intersects(&doc);
-                if ans.is_err() {
-                    return Err("Intersection occurred");
-                }
-            }
-        }
+        self.unique_index.check(&new_doc)?;
+        Self::check_intersects(&new_doc, self.unexpired(Utc::now()))?;
 
-        match self.backend {
-            CollectionBackend::Dir => {
-                let s = self.write_to_dir(&new_doc);
-                if s.is_err() {
-                    return Err("Error writing to DB");
-                }
-            }
-            CollectionBackend::File => {
-                let s: Result<(), &str> = self.write_new_document_to_file(&new_doc);
-                if s.is_err() {
-                    return Err("Error writing to DB");
-                }
-            }
-            CollectionBackend::InMemory => {}
-        }
+        self.commit(vec![WalOp::Insert(new_doc)])
+    }
+
+    /// Update a document. The document is inserted if it does not
+    /// exist, the same as `upsert`. Use `update_existing` to
+    /// error instead.
+    pub fn update(&mut self, updated_doc: T) -> Result<(), Error> {
+        self.upsert(updated_doc)
+    }
 
-        self.documents.insert(new_doc.primary_key(), new_doc);
+    /// Update a document, inserting it if it does not exist.
+    pub fn upsert(&mut self, doc: T) -> Result<(), Error> {
+        let doc = self.before_write(doc, false)?;
+        self.sweep_conflicts(&doc)?;
+        self.validate(&doc)?;
+        self.unique_index.check(&doc)?;
+        Self::check_intersects(&doc, self.unexpired(Utc::now()))?;
 
-        return Ok(());
+        self.commit(vec![WalOp::Update(doc)])
     }
 
-    /// Update a document
-    pub fn update(&mut self, updated_doc: T) -> Result<(), &str> {
-        for (doc_pk, doc) in self.documents.iter() {
-            // No clash on self as you may be updating it.
-            if updated_doc.primary_key() != *doc_pk {
-                let ans = updated_doc.intersects(&doc);
-                match ans {
-                    Ok(()) => {}
-                    Err(_) => return Err("Intersection occurred"),
-                }
-            }
+    /// Update a document that already exists.
+    pub fn update_existing(&mut self, updated_doc: T) -> Result<(), Error> {
+        if self.get_ref(&updated_doc.primary_key()).is_none() {
+            return Err(Error::NotFound);
         }
+        self.upsert(updated_doc)
+    }
 
-        match self.backend {
-            CollectionBackend::Dir => {
-                let s = self.write_to_dir(&updated_doc);
-                if s.is_err() {
-                    return Err("Error writing to DB");
-                }
-            }
-            CollectionBackend::File => {
-                let s: Result<(), &str> = self.write_updated_document_to_file(&updated_doc);
-                if s.is_err() {
-                    return Err("Error writing to DB");
-                }
-            }
-            CollectionBackend::InMemory => {}
+    /// Apply `f` to a copy of a document and persist the result.
+    /// `f` must not change the primary key.
+    pub fn modify(&mut self, pk: &T::Key, f: impl FnOnce(&mut T)) -> Result<(), Error> {
+        let mut doc = self.get_ref(pk).ok_or(Error::NotFound)?.clone();
+        f(&mut doc);
+        if doc.primary_key() != *pk {
+            return Err(Error::Conflict {
+                reason: "modify changed the primary key".to_string(),
+            });
         }
+        self.upsert(doc)
+    }
 
-        self.documents
-            .insert(updated_doc.primary_key(), updated_doc);
+    /// Update a document only if it is still at `revision`, as returned
+    /// by `get_with_revision`. Returns the document's new revision.
+    pub fn update_if_revision(&mut self, updated_doc: T, revision: u64) -> Result<u64, Error> {
+        let pk = updated_doc.primary_key();
+        let actual = self.revision(&pk).ok_or(Error::NotFound)?;
+        if actual != revision {
+            return Err(Error::StaleRevision {
+                expected: revision,
+                actual,
+            });
+        }
+        self.upsert(updated_doc)?;
+        Ok(actual + 1)
+    }
 
-        return Ok(());
+    /// Replace a document with `new` only if it currently equals `expected`.
+    /// Returns whether the document was replaced.
+    pub fn compare_and_swap(&mut self, pk: &T::Key, expected: &T, new: T) -> Result<bool, Error>
+    where
+        T: PartialEq,
+    {
+        let current = self.get_ref(pk).ok_or(Error::NotFound)?;
+        if current != expected {
+            return Ok(false);
+        }
+        if new.primary_key() != *pk {
+            return Err(Error::Conflict {
+                reason: "compare_and_swap changed the primary key".to_string(),
+            });
+        }
+        self.upsert(new)?;
+        Ok(true)
     }
 
     /// Find all documents that meet the criteria.
-    /// Returns a vector of immutable references.
+    /// Returns clones of the documents, see `filter_ref` to borrow them.
     pub fn filter(&self, f: impl Fn(&T) -> bool) -> Vec<T> {
-        self.documents
-            .iter()
-            .filter(|(_, v)| f(v))
-            .map(|(_, v)| v)
-            .cloned()
-            .collect()
+        self.iter().filter(|doc| f(doc)).cloned().collect()
     }
 
     /// Find the first document that satisfies the criteria.
     pub fn find(&self, f: impl Fn(&T) -> bool) -> Option<T> {
+        self.iter().find(|doc| f(doc)).cloned()
+    }
+
+    /// Get a document by its primary key
+    pub fn by_primary_key(&self, pk: &T::Key) -> Option<T> {
+        self.get_ref(pk).cloned()
+    }
+
+    /// Get a document with its revision.
+    pub fn get_with_revision(&self, pk: &T::Key) -> Option<(T, u64)> {
+        Some((self.get_ref(pk)?.clone(), self.revision(pk)?))
+    }
+
+    /// The revision of a document, incremented on every write to it.
+    pub fn revision(&self, pk: &T::Key) -> Option<u64> {
+        self.get_ref(pk)?;
+        self.meta.get(pk).map(|meta| meta.revision)
+    }
+
+    /// What the collection records about a document, including
+    /// soft deleted ones.
+    pub fn metadata(&self, pk: &T::Key) -> Option<DocumentMeta> {
+        self.meta.get(pk).copied()
+    }
+
+    /// The number of documents that can be read, leaving out soft deleted
+    /// and expired ones. This scans the collection.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Whether there are no documents that can be read.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Iterate over the documents in insertion order without cloning them.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let now = Utc::now();
         self.documents
             .iter()
-            .find(|(_, v)| f(v))
-            .map(|(_, v)| v)
-            .cloned()
+            .filter(move |(pk, doc)| self.visible_at(pk, doc, now))
+            .map(|(_, doc)| doc)
+    }
+
+    /// Find all documents that meet the criteria.
+    /// Returns a vector of immutable references.
+    pub fn filter_ref(&self, f: impl Fn(&T) -> bool) -> Vec<&T> {
+        self.iter().filter(|doc| f(doc)).collect()
+    }
+
+    /// Borrow a document by its primary key.
+    pub fn get_ref(&self, pk: &T::Key) -> Option<&T> {
+        self.documents
+            .get(pk)
+            .filter(|doc| self.visible_at(pk, doc, Utc::now()))
     }
 
-    /// Get a document by its uuid
-    pub fn by_primary_key(&self, uuid: &Uuid) -> Option<T> {
-        return self.documents.get(uuid).cloned();
+    /// Call `f` with a document, returning its result,
+    /// or `None` if there is no document with the key.
+    pub fn with<R>(&self, pk: &T::Key, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.get_ref(pk).map(f)
     }
 
-    /// Remove a document from the DB
-    pub fn delete(&mut self, pk: &Uuid) -> Result<(), &str> {
-        let exists = self.documents.contains_key(pk);
+    /// Remove a document from the DB, or mark it deleted
+    /// if soft delete is enabled.
+    pub fn delete(&mut self, pk: &T::Key) -> Result<(), Error> {
+        let exists = self.get_ref(pk).is_some();
         if !exists {
-            return Err("Key does not exist");
+            return Err(Error::NotFound);
         }
 
-        // Potential error between the persistent filestore
-        // and hashmap if the backends are not successful
-        // in writing the data.
-        self.documents.shift_remove(pk);
+        self.before_remove(pk)?;
+        let op = self.delete_op(pk.clone());
+        self.commit(vec![op])
+    }
+
+    /// Sync all writes so far to disk, whatever the durability setting.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.backend.flush()?;
+        if let Some(history) = self.history.as_mut() {
+            history.flush()?;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
 
-        match self.backend {
-            CollectionBackend::Dir => {
-                let s = self.remove_from_dir(pk);
-                if s.is_err() {
-                    return Err("Error removing from DB");
+    /// Change when writes are synced to disk.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+        self.backend.set_durability(durability);
+    }
+
+    /// Reclaim the space left by deleted documents and resizes.
+    pub fn compact(&mut self) -> Result<CompactionStats, Error> {
+        self.backend.compact()
+    }
+
+    /// Apply writes to the backend and then to the documents in memory,
+    /// recording them in the write-ahead log first when it is enabled.
+    /// The writes are synced as the durability requires, and always before
+    /// the log is emptied, ahead of running the after hooks and notifying
+    /// subscribers. If a write, recording the history of the writes or
+    /// the sync fails, the writes already applied are undone.
+    pub(super) fn commit(&mut self, ops: Vec<WalOp<T>>) -> Result<(), Error> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(&ops)?;
+        }
+        let mut applied = Ok(());
+        let mut undo = vec![];
+        let mut changes = vec![];
+        // The writes must be on disk before the log that covers them is emptied.
+        let sync = self.wal.is_some()
+            || match self.durability {
+                Durability::FlushOnEachWrite => true,
+                Durability::GroupCommit { interval } => self.last_sync.elapsed() >= interval,
+                _ => false,
+            };
+        // A lone write needs no undo unless recording its history
+        // or syncing it can fail after it.
+        let undoable = ops.len() > 1 || self.history.is_some() || sync;
+        for op in ops {
+            let inverse = if undoable {
+                Some(self.inverse(&op))
+            } else {
+                None
+            };
+            match self.apply(op, None) {
+                Ok(change) => changes.push(change),
+                Err(err) => {
+                    applied = Err(err);
+                    break;
                 }
             }
-            CollectionBackend::File => {
-                let s: Result<(), &str> = self.rewrite_file();
-                if s.is_err() {
-                    return Err("Error writing to DB");
+            undo.extend(inverse);
+        }
+        // Writes whose history cannot be recorded are undone too.
+        if applied.is_ok() {
+            applied = self.record_history(&changes);
+        }
+        if applied.is_ok() && sync {
+            applied = self.sync();
+        }
+        if applied.is_err() {
+            for (op, meta) in undo.into_iter().rev() {
+                let _ = self.apply(op, meta);
+            }
+        }
+        // The log is emptied whether or not the writes succeeded
+        // as a failed write is reported to the caller.
+        let checkpointed = match self.wal.as_mut() {
+            Some(wal) => wal.checkpoint(),
+            None => Ok(()),
+        };
+        applied.and(checkpointed)?;
+        let events: Vec<ChangeEvent<T>> = changes.into_iter().filter_map(Change::event).collect();
+        self.run_after_hooks(&events);
+        self.publish(events);
+        Ok(())
+    }
+
+    /// Apply a write to the backend and memory. Documents written are
+    /// given `meta`, or the next revision if it is `None`.
+    fn apply(&mut self, op: WalOp<T>, meta: Option<DocumentMeta>) -> Result<Change<T>, Error> {
+        let insert = matches!(op, WalOp::Insert(_));
+        match op {
+            WalOp::Insert(doc) | WalOp::Update(doc) => {
+                let pk = doc.primary_key();
+                let meta = meta.unwrap_or_else(|| self.next_meta(&pk));
+                if insert {
+                    self.backend.persist_insert(&doc, &meta)?;
+                } else {
+                    self.backend.persist_update(&doc, &meta)?;
+                }
+                let old_meta = self.meta.get(&pk).copied();
+                let new = self.tracks_changes().then(|| doc.clone());
+                Ok(Change {
+                    old: self.store(doc, meta).zip(old_meta),
+                    new,
+                    deleted: false,
+                })
+            }
+            WalOp::Delete(pk) => {
+                self.backend.persist_delete(&pk)?;
+                let old_meta = self.meta.get(&pk).copied();
+                Ok(Change {
+                    old: self.unstore(&pk).zip(old_meta),
+                    new: None,
+                    deleted: true,
+                })
+            }
+            WalOp::SoftDelete(pk) => {
+                let doc = self.documents.get(&pk).ok_or(Error::NotFound)?.clone();
+                let old_meta = self.meta.get(&pk).copied();
+                let meta = meta.unwrap_or_else(|| DocumentMeta {
+                    deleted_at: Some(Utc::now()),
+                    ..self.next_meta(&pk)
+                });
+                self.backend.persist_update(&doc, &meta)?;
+                Ok(Change {
+                    old: self.store(doc, meta).zip(old_meta),
+                    new: None,
+                    deleted: true,
+                })
+            }
+        }
+    }
+
+    /// Whether writes need to report the new version of the
+    /// documents they write, for subscribers or after hooks.
+    fn tracks_changes(&self) -> bool {
+        !self.subscribers.is_empty() || self.hooks.has_after()
+    }
+
+    /// The metadata for the next write of a document. Revisions carry
+    /// on from the history if the document was deleted.
+    fn next_meta(&self, pk: &T::Key) -> DocumentMeta {
+        let revision = match (self.meta.get(pk), self.deleted_revisions.get(pk)) {
+            (Some(meta), _) => meta.revision,
+            (None, Some(revision)) => *revision,
+            (None, None) => self.last_revision(pk).unwrap_or(0),
+        };
+        DocumentMeta {
+            revision: revision + 1,
+            written_at: Some(Utc::now()),
+            deleted_at: None,
+        }
+    }
+
+    /// Put a document in memory, keeping the indexes up to date.
+    /// Returns the version it replaced.
+    fn store(&mut self, doc: T, meta: DocumentMeta) -> Option<T> {
+        if let Some(old) = self.documents.get(&doc.primary_key()) {
+            self.unique_index.remove(old);
+            for index in self.indexes.values_mut() {
+                index.remove(old);
+            }
+        }
+        self.unique_index.insert(&doc);
+        for index in self.indexes.values_mut() {
+            index.insert(&doc);
+        }
+        self.deleted_revisions.remove(&doc.primary_key());
+        self.meta.insert(doc.primary_key(), meta);
+        self.documents.insert(doc.primary_key(), doc)
+    }
+
+    /// Remove a document from memory, keeping the indexes up to date.
+    fn unstore(&mut self, pk: &T::Key) -> Option<T> {
+        let old = self.documents.shift_remove(pk)?;
+        if let Some(meta) = self.meta.remove(pk) {
+            if self.history.is_none() {
+                self.deleted_revisions.insert(pk.clone(), meta.revision);
+            }
+        }
+        self.unique_index.remove(&old);
+        for index in self.indexes.values_mut() {
+            index.remove(&old);
+        }
+        Some(old)
+    }
+
+    /// The write that reverts `op`, with the metadata to restore.
+    fn inverse(&self, op: &WalOp<T>) -> (WalOp<T>, Option<DocumentMeta>) {
+        let pk = match op {
+            WalOp::Insert(doc) | WalOp::Update(doc) => doc.primary_key(),
+            WalOp::Delete(pk) | WalOp::SoftDelete(pk) => pk.clone(),
+        };
+        match self.documents.get(&pk) {
+            Some(old) if matches!(op, WalOp::Delete(_)) => {
+                (WalOp::Insert(old.clone()), self.meta.get(&pk).copied())
+            }
+            Some(old) => (WalOp::Update(old.clone()), self.meta.get(&pk).copied()),
+            None => (WalOp::Delete(pk), None),
+        }
+    }
+
+    /// Re-apply a write found in the log on open. The write may already
+    /// have reached the backend, so inserts are applied as updates and
+    /// the revision can advance by more than one.
+    fn replay(&mut self, op: WalOp<T>) -> Result<(), Error> {
+        let op = match op {
+            WalOp::Insert(doc) | WalOp::Update(doc) => WalOp::Update(doc),
+            WalOp::Delete(pk) if self.documents.contains_key(&pk) => WalOp::Delete(pk),
+            WalOp::SoftDelete(pk) if self.documents.contains_key(&pk) => WalOp::SoftDelete(pk),
+            WalOp::Delete(_) | WalOp::SoftDelete(_) => return Ok(()),
+        };
+        self.apply(op, None)?;
+        Ok(())
+    }
+
+    /// Validate every document written from now on with `Validate::validate`,
+    /// before it is checked against the other documents.
+    pub fn enable_validation(&mut self)
+    where
+        T: Validate,
+    {
+        self.validator = Some(<T as Validate>::validate);
+    }
+
+    /// Run the validation enabled by `enable_validation`.
+    pub(super) fn validate(&self, doc: &T) -> Result<(), Error> {
+        match self.validator {
+            Some(validate) => validate(doc).map_err(|errors| Error::Invalid { errors }),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the document against every other document
+    /// given. No clash on self as you may be updating it.
+    pub(super) fn check_intersects<'a>(
+        new_doc: &T,
+        docs: impl Iterator<Item = &'a T>,
+    ) -> Result<(), Error> {
+        if !<T as Document<T>>::CHECK_INTERSECTS {
+            return Ok(());
+        }
+        let pk = new_doc.primary_key();
+        for doc in docs {
+            if pk != doc.primary_key() {
+                if let Err(reason) = new_doc.intersects(doc) {
+                    return Err(Error::Conflict {
+                        reason: reason.to_string(),
+                    });
                 }
             }
-            CollectionBackend::InMemory => {}
         }
+        Ok(())
+    }
+}
 
-        return Ok(());
+impl<T> Drop for Collection<T>
+where
+    T: Document<T> + Debug + Serialize + DeserializeOwned + Clone + Sync + Send,
+{
+    /// Sync writes left waiting for the next group commit.
+    fn drop(&mut self) {
+        if let Durability::GroupCommit { .. } = self.durability {
+            let _ = self.backend.flush();
+            if let Some(history) = self.history.as_mut() {
+                let _ = history.flush();
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use serde::Deserialize;
+    use crate::{ChangeEvent, Sequence, Ulid, UuidV7};
     use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use uuid::Uuid;
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -199,15 +619,17 @@ mod test {
     }
 
     impl Document<User> for User {
+        type Key = Uuid;
+
         fn primary_key(&self) -> Uuid {
-            self.uuid.clone()
+            self.uuid
         }
 
         fn intersects(&self, doc: &User) -> Result<(), &str> {
             if self.name == doc.name {
                 return Err("Email is already in use.");
             }
-            return Ok(());
+            Ok(())
         }
     }
 
@@ -220,132 +642,1363 @@ mod test {
         }
     }
 
-    #[test]
-    fn test_dir_based() {
+    /// A path under `collections/` for a test, with anything left
+    /// there by an earlier run removed.
+    fn test_path(name: &str) -> PathBuf {
         let mut fp = std::env::current_dir().unwrap();
         fp.push("collections");
-        fp.push("users");
-        let _ = fs::remove_dir_all(&fp);
         let _ = fs::create_dir_all(&fp);
-        let mut c = Collection::<User>::new(CollectionBackend::Dir, Some(fp));
+        fp.push(name);
+        let _ = fs::remove_dir_all(&fp);
+        let _ = fs::remove_file(&fp);
+        let _ = fs::remove_file(fp.with_extension("col.history"));
+        fp
+    }
+
+    /// A path for a test of each backend that persists documents.
+    fn persistent_paths(name: &str) -> [(CollectionBackend, PathBuf); 2] {
+        [
+            (CollectionBackend::Dir, test_path(name)),
+            (CollectionBackend::File, test_path(&format!("{}.col", name))),
+        ]
+    }
+
+    #[test]
+    fn test_dir_based() {
+        let fp = test_path("users");
+        let mut c = Collection::<User>::new(CollectionBackend::Dir, Some(fp)).unwrap();
 
         let user = User::new("bob".to_string());
         let mut user_cloned = user.clone();
         let res = c.insert(user);
-        if res.is_err() {
-            println!("{:?}", res.unwrap())
+        if let Err(e) = &res {
+            println!("{:?}", e)
         }
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         let user = User::new("bill".to_string());
-        let b_uuid = user.uuid.clone();
+        let b_uuid = user.uuid;
         let res = c.insert(user);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         user_cloned.name = "Trevor".to_string();
         let res = c.update(user_cloned);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         let user = User::new("dan".to_string());
-        let uuid = user.uuid.clone();
+        let uuid = user.uuid;
         let res = c.insert(user);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         let get_user = c.by_primary_key(&uuid);
-        if get_user.is_some() {
-            println!("{:?}", get_user.unwrap());
+        if let Some(user) = get_user {
+            println!("{:?}", user);
         }
 
         let del = c.delete(&b_uuid);
-        assert_eq!(del.is_ok(), true);
+        assert!(del.is_ok());
 
         let get_user = c.by_primary_key(&uuid);
-        if get_user.is_some() {
-            println!("{:?}", get_user.unwrap());
+        if let Some(user) = get_user {
+            println!("{:?}", user);
         }
     }
 
     #[test]
     fn test_in_memory() {
-        let mut c = Collection::<User>::new(CollectionBackend::InMemory, None);
+        let mut c = Collection::<User>::new(CollectionBackend::InMemory, None).unwrap();
 
         let user = User::new("bob".to_string());
         let mut user_cloned = user.clone();
         let res = c.insert(user);
-        if res.is_err() {
-            println!("{:?}", res.unwrap())
+        if let Err(e) = &res {
+            println!("{:?}", e)
         }
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         let user = User::new("bill".to_string());
-        let b_uuid = user.uuid.clone();
+        let b_uuid = user.uuid;
         let res = c.insert(user);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         user_cloned.name = "Trevor".to_string();
         let res = c.update(user_cloned);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         let user = User::new("dan".to_string());
-        let uuid = user.uuid.clone();
+        let uuid = user.uuid;
         let res = c.insert(user);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         let get_user = c.by_primary_key(&uuid);
-        if get_user.is_some() {
-            println!("{:?}", get_user.unwrap());
+        if let Some(user) = get_user {
+            println!("{:?}", user);
         }
 
         let del = c.delete(&b_uuid);
-        assert_eq!(del.is_ok(), true);
+        assert!(del.is_ok());
 
         let get_user = c.by_primary_key(&uuid);
-        if get_user.is_some() {
-            println!("{:?}", get_user.unwrap());
+        if let Some(user) = get_user {
+            println!("{:?}", user);
         }
     }
 
     #[test]
     fn test_file_based() {
-        let mut fp = std::env::current_dir().unwrap();
-        fp.push("collections");
-        fp.push("user.col");
-        let _ = fs::remove_file(fp.clone());
-        let mut c = Collection::<User>::new(CollectionBackend::File, Some(fp));
+        let fp = test_path("user.col");
+        let mut c = Collection::<User>::new(CollectionBackend::File, Some(fp)).unwrap();
 
         let user = User::new("bob".to_string());
         let mut user_cloned = user.clone();
         let res = c.insert(user);
-        if res.is_err() {
-            println!("{:?}", res.unwrap())
+        if let Err(e) = &res {
+            println!("{:?}", e)
         }
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         let user = User::new("bill".to_string());
-        let b_uuid = user.uuid.clone();
+        let b_uuid = user.uuid;
         let res = c.insert(user);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         user_cloned.name = "Trevor".to_string();
         let res = c.update(user_cloned);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         let user = User::new("dan".to_string());
-        let uuid = user.uuid.clone();
+        let uuid = user.uuid;
         let res = c.insert(user);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
 
         let get_user = c.by_primary_key(&uuid);
-        if get_user.is_some() {
-            println!("{:?}", get_user.unwrap());
+        if let Some(user) = get_user {
+            println!("{:?}", user);
         }
 
         let del = c.delete(&b_uuid);
-        assert_eq!(del.is_ok(), true);
+        assert!(del.is_ok());
 
         let get_user = c.by_primary_key(&uuid);
-        if get_user.is_some() {
-            println!("{:?}", get_user.unwrap());
+        if let Some(user) = get_user {
+            println!("{:?}", user);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut c = Collection::<User>::new(CollectionBackend::InMemory, None).unwrap();
+
+        let user = User::new("bob".to_string());
+        c.insert(user.clone()).unwrap();
+
+        let res = c.insert(user.clone());
+        assert!(matches!(res, Err(Error::DuplicateKey)));
+
+        let res = c.insert(User::new("bob".to_string()));
+        match res {
+            Err(Error::Conflict { reason }) => assert_eq!(reason, "Email is already in use."),
+            _ => panic!("expected a conflict"),
+        }
+
+        let res = c.delete(&Uuid::new_v4());
+        assert!(matches!(res, Err(Error::NotFound)));
+
+        let res = Collection::<User>::new(CollectionBackend::File, None);
+        assert!(matches!(res, Err(Error::MissingPath)));
+    }
+
+    #[derive(Default)]
+    struct VecBackend {
+        docs: Arc<RwLock<Vec<User>>>,
+        flushes: Arc<AtomicUsize>,
+        fail_on: Option<Uuid>,
+        fail_flush: bool,
+        /// Records the length of this write-ahead log on each flush.
+        wal: Option<(PathBuf, Arc<RwLock<Vec<u64>>>)>,
+    }
+
+    impl StorageBackend<User> for VecBackend {
+        fn load_all(&mut self) -> Result<Vec<(User, DocumentMeta)>, Error> {
+            let docs = self.docs.read().unwrap();
+            Ok(docs
+                .iter()
+                .map(|d| (d.clone(), DocumentMeta::default()))
+                .collect())
+        }
+
+        fn persist_insert(&mut self, doc: &User, _meta: &DocumentMeta) -> Result<(), Error> {
+            if self.fail_on == Some(doc.uuid) {
+                return Err(std::io::Error::other("disk full").into());
+            }
+            self.docs.write().unwrap().push(doc.clone());
+            Ok(())
+        }
+
+        fn persist_update(&mut self, doc: &User, _meta: &DocumentMeta) -> Result<(), Error> {
+            let mut docs = self.docs.write().unwrap();
+            docs.retain(|d| d.uuid != doc.uuid);
+            docs.push(doc.clone());
+            Ok(())
+        }
+
+        fn persist_delete(&mut self, pk: &Uuid) -> Result<(), Error> {
+            self.docs.write().unwrap().retain(|d| d.uuid != *pk);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            if self.fail_flush {
+                return Err(std::io::Error::other("disk full").into());
+            }
+            if let Some((path, lengths)) = self.wal.as_ref() {
+                lengths.write().unwrap().push(fs::metadata(path)?.len());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_backend() {
+        let backend = VecBackend::default();
+        let docs = backend.docs.clone();
+        let mut c = Collection::<User>::with_backend(Box::new(backend)).unwrap();
+
+        let bob = User::new("bob".to_string());
+        let bill = User::new("bill".to_string());
+        c.insert(bob.clone()).unwrap();
+        c.insert(bill.clone()).unwrap();
+        c.delete(&bob.uuid).unwrap();
+        assert_eq!(docs.read().unwrap().len(), 1);
+
+        let reopened = Collection::<User>::with_backend(Box::new(VecBackend {
+            docs,
+            ..Default::default()
+        }))
+        .unwrap();
+        assert!(reopened.by_primary_key(&bill.uuid).is_some());
+        assert!(reopened.by_primary_key(&bob.uuid).is_none());
+    }
+
+    #[test]
+    fn test_file_reopen_after_resize() {
+        let fp = test_path("resized.col");
+
+        let mut c = Collection::<User>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        let bob = User::new("bob".to_string());
+        let long = User::new("l".repeat(200));
+        c.insert(bob.clone()).unwrap();
+        c.insert(long.clone()).unwrap();
+        drop(c);
+
+        let mut c = Collection::<User>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        assert_eq!(c.len(), 2);
+        let mut bob = c.by_primary_key(&bob.uuid).unwrap();
+        bob.name = "robert".to_string();
+        c.update(bob.clone()).unwrap();
+        drop(c);
+
+        let c = Collection::<User>::new(CollectionBackend::File, Some(fp)).unwrap();
+        assert_eq!(c.by_primary_key(&bob.uuid).unwrap().name, "robert");
+        assert_eq!(c.by_primary_key(&long.uuid).unwrap().name, long.name);
+    }
+
+    #[test]
+    fn test_file_non_ascii() {
+        let fp = test_path("non_ascii.col");
+
+        let mut c = Collection::<User>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        let zoe = User::new("Zoë Brontë 🦀".to_string());
+        let wide = User::new("é".repeat(100));
+        c.insert(zoe.clone()).unwrap();
+        c.insert(wide.clone()).unwrap();
+        drop(c);
+
+        let c = Collection::<User>::new(CollectionBackend::File, Some(fp)).unwrap();
+        assert_eq!(c.by_primary_key(&zoe.uuid).unwrap().name, zoe.name);
+        assert_eq!(c.by_primary_key(&wide.uuid).unwrap().name, wide.name);
+    }
+
+    #[test]
+    fn test_file_without_header() {
+        let fp = test_path("legacy.col");
+
+        let bob = User::new("bob".to_string());
+        let json = serde_json::to_string(&bob).unwrap();
+        fs::write(&fp, format!("{:width$}\n", json, width = 192)).unwrap();
+
+        let c = Collection::<User>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        assert_eq!(c.by_primary_key(&bob.uuid).unwrap().name, "bob");
+        drop(c);
+
+        let c = Collection::<User>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        assert_eq!(c.len(), 1);
+
+        fs::write(&fp, "{\"uuid\":\"x\"}\n{}\n").unwrap();
+        let res = Collection::<User>::new(CollectionBackend::File, Some(fp));
+        assert!(matches!(res, Err(Error::InvalidFile { .. })));
+    }
+
+    #[test]
+    fn test_file_reuses_deleted_rows() {
+        let fp = test_path("tombstones.col");
+
+        let mut c = Collection::<User>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        let bob = User::new("bob".to_string());
+        let bill = User::new("bill".to_string());
+        c.insert(bob.clone()).unwrap();
+        c.insert(bill.clone()).unwrap();
+        let length = fs::metadata(&fp).unwrap().len();
+
+        c.delete(&bob.uuid).unwrap();
+        assert_eq!(fs::metadata(&fp).unwrap().len(), length);
+        drop(c);
+
+        let mut c = Collection::<User>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        assert_eq!(c.len(), 1);
+        let dan = User::new("dan".to_string());
+        c.insert(dan.clone()).unwrap();
+        assert_eq!(fs::metadata(&fp).unwrap().len(), length);
+        drop(c);
+
+        let c = Collection::<User>::new(CollectionBackend::File, Some(fp)).unwrap();
+        assert!(c.by_primary_key(&bill.uuid).is_some());
+        assert!(c.by_primary_key(&dan.uuid).is_some());
+        assert!(c.by_primary_key(&bob.uuid).is_none());
+    }
+
+    #[test]
+    fn test_file_compact() {
+        let fp = test_path("compact.col");
+
+        let mut c = Collection::<User>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        let users: Vec<User> = (0..4).map(|i| User::new(format!("user {}", i))).collect();
+        for user in users.iter() {
+            c.insert(user.clone()).unwrap();
+        }
+        c.delete(&users[0].uuid).unwrap();
+        c.delete(&users[2].uuid).unwrap();
+
+        let stats = c.compact().unwrap();
+        assert!(stats.bytes_after < stats.bytes_before);
+        assert_eq!(stats.bytes_after, fs::metadata(&fp).unwrap().len());
+        assert_eq!(stats.rows_moved, 2);
+        drop(c);
+
+        let c = Collection::<User>::new(CollectionBackend::File, Some(fp)).unwrap();
+        assert_eq!(c.len(), 2);
+        assert!(c.by_primary_key(&users[1].uuid).is_some());
+        assert!(c.by_primary_key(&users[3].uuid).is_some());
+    }
+
+    #[test]
+    fn test_file_auto_compact() {
+        let fp = test_path("auto_compact.col");
+
+        let mut backend = FileBackend::new(fp.clone()).unwrap();
+        backend.auto_compact_ratio = Some(0.5);
+        let mut c = Collection::<User>::with_backend(Box::new(backend)).unwrap();
+        let users: Vec<User> = (0..4).map(|i| User::new(format!("user {}", i))).collect();
+        for user in users.iter() {
+            c.insert(user.clone()).unwrap();
+        }
+        let length = fs::metadata(&fp).unwrap().len();
+        c.delete(&users[0].uuid).unwrap();
+        c.delete(&users[1].uuid).unwrap();
+        assert_eq!(fs::metadata(&fp).unwrap().len(), length);
+
+        // A compaction that fails does not fail the delete.
+        let mut blocker = fp.clone().into_os_string();
+        blocker.push(".tmp");
+        fs::create_dir_all(&blocker).unwrap();
+        c.delete(&users[2].uuid).unwrap();
+        fs::remove_dir(&blocker).unwrap();
+        assert!(c.by_primary_key(&users[2].uuid).is_none());
+        assert_eq!(fs::metadata(&fp).unwrap().len(), length);
+
+        c.delete(&users[3].uuid).unwrap();
+        assert!(fs::metadata(&fp).unwrap().len() < length);
+        drop(c);
+        let c = Collection::<User>::new(CollectionBackend::File, Some(fp)).unwrap();
+        assert!(c.is_empty());
+    }
+
+    #[test]
+    fn test_dir_cleans_up_partial_writes() {
+        let fp = test_path("partial_writes");
+
+        let mut c = Collection::<User>::new(CollectionBackend::Dir, Some(fp.clone())).unwrap();
+        let bob = User::new("bob".to_string());
+        c.insert(bob.clone()).unwrap();
+        drop(c);
+
+        let tmp = fp.join(format!("{}.json.tmp", Uuid::new_v4()));
+        fs::write(&tmp, "{\"uuid\":").unwrap();
+
+        let c = Collection::<User>::new(CollectionBackend::Dir, Some(fp.clone())).unwrap();
+        assert_eq!(c.len(), 1);
+        assert!(!tmp.exists());
+        assert_eq!(fs::read_dir(&fp).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_wal_replay() {
+        let fp = test_path("wal.col");
+        let wal_path = test_path("wal.col.wal");
+        let options = CollectionOptions {
+            wal_path: Some(wal_path.clone()),
+            ..Default::default()
+        };
+
+        let backend = Box::new(FileBackend::new(fp.clone()).unwrap());
+        let mut c = Collection::<User>::with_options(backend, options.clone()).unwrap();
+        let bob = User::new("bob".to_string());
+        c.insert(bob.clone()).unwrap();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+        drop(c);
+
+        // Writes logged before a crash that never reached the file.
+        let bill = User::new("bill".to_string());
+        let mut wal = Wal::open(wal_path.clone()).unwrap();
+        wal.append(&[WalOp::Insert(bill.clone())]).unwrap();
+        wal.append::<User>(&[WalOp::Delete(bob.uuid)]).unwrap();
+        drop(wal);
+
+        let backend = Box::new(FileBackend::new(fp.clone()).unwrap());
+        let c = Collection::<User>::with_options(backend, options).unwrap();
+        assert!(c.by_primary_key(&bill.uuid).is_some());
+        assert!(c.by_primary_key(&bob.uuid).is_none());
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+        drop(c);
+
+        let c = Collection::<User>::new(CollectionBackend::File, Some(fp)).unwrap();
+        assert_eq!(c.len(), 1);
+
+        // The backend is flushed while the log still holds the write.
+        let wal_path = test_path("wal_order.wal");
+        let lengths = Arc::new(RwLock::new(vec![]));
+        let backend = VecBackend {
+            wal: Some((wal_path.clone(), lengths.clone())),
+            ..Default::default()
+        };
+        let options = CollectionOptions {
+            wal_path: Some(wal_path.clone()),
+            durability: Durability::Manual,
+        };
+        let mut c = Collection::<User>::with_options(Box::new(backend), options).unwrap();
+        lengths.write().unwrap().clear();
+        c.insert(User::new("bob".to_string())).unwrap();
+        assert!(lengths.read().unwrap().iter().all(|length| *length > 0));
+        assert_eq!(lengths.read().unwrap().len(), 1);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_durability() {
+        let flushes_with = |durability: Durability| {
+            let backend = VecBackend::default();
+            let flushes = backend.flushes.clone();
+            let options = CollectionOptions {
+                durability,
+                ..Default::default()
+            };
+            let mut c = Collection::<User>::with_options(Box::new(backend), options).unwrap();
+            c.insert(User::new("bob".to_string())).unwrap();
+            c.insert(User::new("bill".to_string())).unwrap();
+            let before_sync = flushes.load(Ordering::SeqCst);
+            c.sync().unwrap();
+            (before_sync, flushes.load(Ordering::SeqCst))
+        };
+
+        assert_eq!(flushes_with(Durability::None), (0, 1));
+        assert_eq!(flushes_with(Durability::Manual), (0, 1));
+        assert_eq!(flushes_with(Durability::FlushOnEachWrite), (2, 3));
+        let interval = Duration::from_secs(3600);
+        assert_eq!(flushes_with(Durability::GroupCommit { interval }), (0, 1));
+        let backend = VecBackend::default();
+        let flushes = backend.flushes.clone();
+        let options = CollectionOptions {
+            durability: Durability::GroupCommit { interval },
+            ..Default::default()
+        };
+        let mut c = Collection::<User>::with_options(Box::new(backend), options).unwrap();
+        c.insert(User::new("bob".to_string())).unwrap();
+        drop(c);
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+        let interval = Duration::ZERO;
+        assert_eq!(flushes_with(Durability::GroupCommit { interval }), (2, 3));
+
+        // A write that cannot be synced is undone before anyone hears of it.
+        let backend = VecBackend {
+            fail_flush: true,
+            ..Default::default()
+        };
+        let docs = backend.docs.clone();
+        let mut c = Collection::<User>::with_backend(Box::new(backend)).unwrap();
+        let inserted = Arc::new(AtomicUsize::new(0));
+        let counter = inserted.clone();
+        c.after_insert(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let events = c.subscribe();
+        assert!(c.insert(User::new("bob".to_string())).is_err());
+        assert!(c.is_empty());
+        assert!(docs.read().unwrap().is_empty());
+        assert_eq!(inserted.load(Ordering::SeqCst), 0);
+        assert!(events.try_recv().is_err());
+
+        // The dir backend syncs the directory on `sync` rather than on each write.
+        let fp = test_path("test_durability");
+        let mut c = Collection::<User>::new(CollectionBackend::Dir, Some(fp.clone())).unwrap();
+        c.set_durability(Durability::Manual);
+        let bob = User::new("bob".to_string());
+        c.insert(bob.clone()).unwrap();
+        c.insert(User::new("bill".to_string())).unwrap();
+        c.delete(&bob.uuid).unwrap();
+        c.sync().unwrap();
+        let c = Collection::<User>::new(CollectionBackend::Dir, Some(fp)).unwrap();
+        assert_eq!(c.len(), 1);
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut c = Collection::<User>::new(CollectionBackend::InMemory, None).unwrap();
+        let bob = User::new("bob".to_string());
+        c.insert(bob.clone()).unwrap();
+
+        // Clashes with another staged document.
+        let res = c
+            .batch()
+            .insert(User::new("bill".to_string()))
+            .insert(User::new("bill".to_string()))
+            .commit();
+        assert!(matches!(res, Err(Error::Conflict { .. })));
+        assert_eq!(c.len(), 1);
+
+        // The name is free once bob is deleted in the same batch.
+        let mut renamed = User::new("bob".to_string());
+        renamed.uuid = Uuid::new_v4();
+        c.batch()
+            .delete(&bob.uuid)
+            .insert(renamed.clone())
+            .commit()
+            .unwrap();
+        assert!(c.by_primary_key(&bob.uuid).is_none());
+        assert!(c.by_primary_key(&renamed.uuid).is_some());
+    }
+
+    #[test]
+    fn test_batch_rollback() {
+        let dan = User::new("dan".to_string());
+        let backend = VecBackend {
+            fail_on: Some(dan.uuid),
+            ..Default::default()
+        };
+        let docs = backend.docs.clone();
+        let mut c = Collection::<User>::with_backend(Box::new(backend)).unwrap();
+        let mut bob = User::new("bob".to_string());
+        c.insert(bob.clone()).unwrap();
+
+        let bill = User::new("bill".to_string());
+        bob.name = "robert".to_string();
+        let res = c
+            .batch()
+            .insert(bill.clone())
+            .update(bob.clone())
+            .insert(dan)
+            .commit();
+        assert!(matches!(res, Err(Error::Io { .. })));
+        assert_eq!(c.len(), 1);
+        assert_eq!(c.by_primary_key(&bob.uuid).unwrap().name, "bob");
+        assert_eq!(c.revision(&bob.uuid), Some(1));
+        let docs = docs.read().unwrap();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].name, "bob");
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Account {
+        uuid: Uuid,
+        email: String,
+    }
+
+    impl Document<Account> for Account {
+        const CHECK_INTERSECTS: bool = false;
+
+        type Key = Uuid;
+
+        fn primary_key(&self) -> Uuid {
+            self.uuid
+        }
+
+        fn unique_keys(&self) -> Vec<(&'static str, serde_json::Value)> {
+            vec![("email", self.email.clone().into())]
+        }
+    }
+
+    impl Account {
+        fn new(email: &str) -> Self {
+            Account {
+                uuid: Uuid::new_v4(),
+                email: email.to_string(),
+            }
+        }
+    }
+
+    impl Validate for Account {
+        fn validate(&self) -> Result<(), ValidationErrors> {
+            let mut errors = ValidationErrors::new();
+            if self.email.is_empty() {
+                errors.add("email", "must not be empty");
+            }
+            if !self.email.contains('@') {
+                errors.add("email", "must contain @");
+            }
+            errors.into_result()
+        }
+    }
+
+    #[test]
+    fn test_unique_keys() {
+        let mut c = Collection::<Account>::new(CollectionBackend::InMemory, None).unwrap();
+        let mut a = Account::new("a@e.g.com");
+        let mut b = Account::new("b@e.g.com");
+        c.insert(a.clone()).unwrap();
+        c.insert(b.clone()).unwrap();
+
+        let res = c.insert(Account::new("a@e.g.com"));
+        assert!(matches!(
+            res,
+            Err(Error::UniqueViolation { key: "email", .. })
+        ));
+        c.update(a.clone()).unwrap();
+        b.email = a.email.clone();
+        assert!(c.update(b.clone()).is_err());
+
+        // Taking an email released earlier in the same batch.
+        a.email = "c@e.g.com".to_string();
+        c.batch()
+            .update(a.clone())
+            .update(b.clone())
+            .commit()
+            .unwrap();
+        assert_eq!(c.by_primary_key(&b.uuid).unwrap().email, "a@e.g.com");
+
+        c.delete(&a.uuid).unwrap();
+        c.insert(Account::new("c@e.g.com")).unwrap();
+    }
+
+    #[test]
+    fn test_secondary_indexes() {
+        let mut c = Collection::<User>::new(CollectionBackend::InMemory, None).unwrap();
+        c.create_index("name", |u: &User| u.name.clone());
+        let mut bob = User::new("bob".to_string());
+        c.insert(bob.clone()).unwrap();
+        c.insert(User::new("bill".to_string())).unwrap();
+        c.create_btree_index("initial", |u: &User| u.name.chars().next());
+        c.insert(User::new("dan".to_string())).unwrap();
+
+        assert_eq!(c.by_index("name", &"bob".to_string()).unwrap().len(), 1);
+        assert_eq!(c.by_index("initial", &Some('b')).unwrap().len(), 2);
+        let names: Vec<String> = c
+            .range_index("initial", Some('c')..)
+            .unwrap()
+            .into_iter()
+            .map(|u| u.name)
+            .collect();
+        assert_eq!(names, vec!["dan"]);
+
+        bob.name = "robert".to_string();
+        c.update(bob.clone()).unwrap();
+        assert!(c.by_index("name", &"bob".to_string()).unwrap().is_empty());
+        assert_eq!(c.by_index("name", &"robert".to_string()).unwrap().len(), 1);
+        c.delete(&bob.uuid).unwrap();
+        assert!(c.by_index("initial", &Some('r')).unwrap().is_empty());
+
+        assert!(matches!(
+            c.by_index("name", &"bob"),
+            Err(Error::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            c.range_index("name", "a".to_string()..),
+            Err(Error::TypeMismatch { .. })
+        ));
+        c.drop_index("name").unwrap();
+        assert!(matches!(
+            c.by_index("name", &"bob".to_string()),
+            Err(Error::IndexNotFound { .. })
+        ));
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Reading {
+        uuid: Uuid,
+        at: chrono::DateTime<chrono::Utc>,
+    }
+
+    impl Document<Reading> for Reading {
+        type Key = Uuid;
+
+        fn primary_key(&self) -> Uuid {
+            self.uuid
+        }
+    }
+
+    #[test]
+    fn test_time_index() {
+        let mut c = Collection::<Reading>::new(CollectionBackend::InMemory, None).unwrap();
+        c.create_time_index("at", |r: &Reading| r.at);
+        let start = chrono::Utc::now();
+        let times: Vec<_> = (0..5)
+            .map(|i| start + chrono::Duration::minutes(i))
+            .collect();
+        // Insert out of order to check results come back sorted.
+        for i in [3, 0, 4, 1, 2] {
+            c.insert(Reading {
+                uuid: Uuid::new_v4(),
+                at: times[i],
+            })
+            .unwrap();
+        }
+        let ats = |readings: Vec<Reading>| -> Vec<_> { readings.iter().map(|r| r.at).collect() };
+
+        assert_eq!(
+            ats(c.time_range("at", times[1]..times[3]).unwrap()),
+            times[1..3]
+        );
+        assert_eq!(ats(c.latest("at", 2).unwrap()), vec![times[4], times[3]]);
+        assert_eq!(ats(c.before("at", times[1]).unwrap()), vec![times[0]]);
+        assert_eq!(ats(c.after("at", times[3]).unwrap()), vec![times[4]]);
+
+        c.enable_soft_delete();
+        let newest = c.latest("at", 1).unwrap().remove(0);
+        c.delete(&newest.uuid).unwrap();
+        assert_eq!(ats(c.latest("at", 2).unwrap()), vec![times[3], times[2]]);
+    }
+
+    #[test]
+    fn test_query() {
+        let mut c = Collection::<User>::new(CollectionBackend::InMemory, None).unwrap();
+        for name in ["eve", "bob", "dan", "amy", "cat"] {
+            c.insert(User::new(name.to_string())).unwrap();
+        }
+        let names =
+            |users: Vec<User>| -> Vec<String> { users.into_iter().map(|u| u.name).collect() };
+
+        let page = c
+            .query()
+            .filter(|u| u.name != "cat")
+            .sort_by_key(|u| u.name.clone())
+            .skip(1)
+            .limit(2)
+            .collect();
+        assert_eq!(names(page), vec!["bob", "dan"]);
+        assert_eq!(c.query().filter(|u| u.name.as_str() < "c").count(), 2);
+        assert!(c.query().filter(|u| u.name == "amy").exists());
+        assert!(!c.query().filter(|u| u.name == "zed").exists());
+        let first = c.query().sort_by_key(|u| u.name.clone()).first();
+        assert_eq!(first.unwrap().name, "amy");
+
+        c.create_btree_index("name", |u: &User| u.name.clone());
+        let page = c
+            .query()
+            .where_range("name", "b".to_string().."e".to_string())
+            .unwrap()
+            .filter(|u| u.name != "dan")
+            .collect();
+        assert_eq!(names(page), vec!["bob", "cat"]);
+        let found = c
+            .query()
+            .where_index("name", &"eve".to_string())
+            .unwrap()
+            .first();
+        assert!(found.is_some());
+    }
+
+    #[test]
+    fn test_borrowing_reads() {
+        let shared = Collection::<User>::new_arc(CollectionBackend::InMemory, None).unwrap();
+        let bob = User::new("bob".to_string());
+        {
+            let mut c = shared.write().unwrap();
+            c.insert(bob.clone()).unwrap();
+            c.insert(User::new("amy".to_string())).unwrap();
+        }
+
+        let c = shared.read().unwrap();
+        let names: Vec<&str> = c.iter().map(|u| u.name.as_str()).collect();
+        assert_eq!(names, vec!["bob", "amy"]);
+        assert_eq!(c.filter_ref(|u| u.name == "amy").len(), 1);
+        assert_eq!(c.get_ref(&bob.uuid).unwrap().name, "bob");
+        assert_eq!(c.with(&bob.uuid, |u| u.name.len()), Some(3));
+        assert_eq!(c.with(&Uuid::new_v4(), |u| u.name.len()), None);
+        drop(c);
+
+        let doc = Collection::read_guard(&shared, &bob.uuid).unwrap().unwrap();
+        assert_eq!(doc.name, "bob");
+        assert!(shared.try_write().is_err());
+        drop(doc);
+        assert!(Collection::read_guard(&shared, &Uuid::new_v4())
+            .unwrap()
+            .is_none());
+        shared.write().unwrap().delete(&bob.uuid).unwrap();
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Setting {
+        name: String,
+        value: u64,
+    }
+
+    impl Document<Setting> for Setting {
+        type Key = String;
+
+        fn primary_key(&self) -> String {
+            self.name.clone()
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+    struct SampleKey {
+        device: u64,
+        at: i64,
+    }
+
+    impl std::fmt::Display for SampleKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}@{}", self.device, self.at)
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Sample {
+        key: SampleKey,
+        value: f64,
+    }
+
+    impl Document<Sample> for Sample {
+        type Key = SampleKey;
+
+        fn primary_key(&self) -> SampleKey {
+            self.key.clone()
+        }
+    }
+
+    #[test]
+    fn test_generic_keys() {
+        let fp = test_path("test_generic_keys");
+
+        let names = ["theme", "Theme", "../escape", "a/b.json", "ünï"];
+        let mut c = Collection::<Setting>::new(CollectionBackend::Dir, Some(fp.clone())).unwrap();
+        for (value, name) in names.iter().enumerate() {
+            c.insert(Setting {
+                name: name.to_string(),
+                value: value as u64,
+            })
+            .unwrap();
+        }
+        c.delete(&"a/b.json".to_string()).unwrap();
+        assert_eq!(std::fs::read_dir(&fp).unwrap().count(), 4);
+        assert!(fp.join("theme.json").exists());
+        assert!(fp.join("%2E%2E%2Fescape.json").exists());
+
+        let c = Collection::<Setting>::new(CollectionBackend::Dir, Some(fp.clone())).unwrap();
+        assert_eq!(c.len(), 4);
+        assert_eq!(c.by_primary_key(&"Theme".to_string()).unwrap().value, 1);
+        assert_eq!(c.by_primary_key(&"../escape".to_string()).unwrap().value, 2);
+
+        let fp = test_path("test_generic_keys.col");
+        let key = |at| SampleKey { device: 7, at };
+        let mut c = Collection::<Sample>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        for at in 0..3 {
+            c.insert(Sample {
+                key: key(at),
+                value: at as f64,
+            })
+            .unwrap();
+        }
+        c.delete(&key(1)).unwrap();
+        let res = c.insert(Sample {
+            key: key(0),
+            value: 9.0,
+        });
+        assert!(matches!(res, Err(Error::DuplicateKey)));
+
+        let c = Collection::<Sample>::new(CollectionBackend::File, Some(fp.clone())).unwrap();
+        let keys: Vec<&SampleKey> = c.documents.keys().collect();
+        assert_eq!(keys, vec![&key(0), &key(2)]);
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Ticket {
+        id: u64,
+        title: String,
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    }
+
+    impl Document<Ticket> for Ticket {
+        type Key = u64;
+
+        fn primary_key(&self) -> u64 {
+            self.id
+        }
+
+        fn expires_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+            self.expires_at
+        }
+    }
+
+    impl Ticket {
+        fn new(id: u64, title: &str) -> Self {
+            Ticket {
+                id,
+                title: title.to_string(),
+                expires_at: None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_generators() {
+        let new_ticket = |id| Ticket::new(id, "todo");
+        let mut c = Collection::<Ticket>::new(CollectionBackend::InMemory, None).unwrap();
+        assert!(matches!(
+            c.insert_new(new_ticket),
+            Err(Error::MissingKeyGenerator)
+        ));
+
+        for (backend, fp) in persistent_paths("test_key_generators") {
+            let mut c = Collection::<Ticket>::new(backend, Some(fp.clone())).unwrap();
+            c.set_key_generator(Sequence::default()).unwrap();
+            c.insert(new_ticket(2)).unwrap();
+            assert_eq!(c.insert_new(new_ticket).unwrap(), 1);
+            assert_eq!(c.insert_new(new_ticket).unwrap(), 3);
+            c.delete(&3).unwrap();
+            let res = c.insert_new(|_| new_ticket(1));
+            assert!(matches!(res, Err(Error::Conflict { .. })));
+
+            let mut c = Collection::<Ticket>::new(backend, Some(fp.clone())).unwrap();
+            c.set_key_generator(Sequence::default()).unwrap();
+            assert_eq!(c.insert_new(new_ticket).unwrap(), 5);
+            assert_eq!(c.len(), 3);
+        }
+
+        let mut c = Collection::<User>::new(CollectionBackend::InMemory, None).unwrap();
+        c.set_key_generator(UuidV7).unwrap();
+        let first = c
+            .insert_new(|uuid| User {
+                uuid,
+                name: "amy".to_string(),
+            })
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        let second = c
+            .insert_new(|uuid| User {
+                uuid,
+                name: "bob".to_string(),
+            })
+            .unwrap();
+        assert!(first < second);
+
+        let mut c = Collection::<Setting>::new(CollectionBackend::InMemory, None).unwrap();
+        c.set_key_generator(Ulid::default()).unwrap();
+        let keys: Vec<String> = (0..100)
+            .map(|value| c.insert_new(|name| Setting { name, value }).unwrap())
+            .collect();
+        assert!(keys.iter().all(|key| key.len() == 26));
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_update_operations() {
+        let mut c = Collection::<Ticket>::new(CollectionBackend::InMemory, None).unwrap();
+        let ticket = Ticket::new;
+
+        let res = c.update_existing(ticket(1, "a"));
+        assert!(matches!(res, Err(Error::NotFound)));
+        c.upsert(ticket(1, "a")).unwrap();
+        c.upsert(ticket(1, "b")).unwrap();
+        c.update_existing(ticket(1, "c")).unwrap();
+        assert_eq!(c.by_primary_key(&1).unwrap().title, "c");
+
+        c.modify(&1, |t| t.title.push('d')).unwrap();
+        assert_eq!(c.by_primary_key(&1).unwrap().title, "cd");
+        assert!(matches!(c.modify(&2, |_| ()), Err(Error::NotFound)));
+        let res = c.modify(&1, |t| t.id = 2);
+        assert!(matches!(res, Err(Error::Conflict { .. })));
+        assert_eq!(c.len(), 1);
+
+        let current = c.by_primary_key(&1).unwrap();
+        assert!(c.compare_and_swap(&1, &current, ticket(1, "e")).unwrap());
+        assert!(!c.compare_and_swap(&1, &current, ticket(1, "f")).unwrap());
+        assert_eq!(c.by_primary_key(&1).unwrap().title, "e");
+    }
+
+    #[test]
+    fn test_revisions() {
+        for (backend, fp) in persistent_paths("test_revisions") {
+            let mut c = Collection::<User>::new(backend, Some(fp.clone())).unwrap();
+            let bob = User::new("bob".to_string());
+            c.insert(bob.clone()).unwrap();
+            let (mut first, revision) = c.get_with_revision(&bob.uuid).unwrap();
+            assert_eq!(revision, 1);
+            let (mut second, _) = c.get_with_revision(&bob.uuid).unwrap();
+
+            first.name = "robert".to_string();
+            assert_eq!(c.update_if_revision(first, revision).unwrap(), 2);
+            second.name = "bobby".to_string();
+            let res = c.update_if_revision(second, revision);
+            assert!(matches!(
+                res,
+                Err(Error::StaleRevision {
+                    expected: 1,
+                    actual: 2
+                })
+            ));
+
+            // A document written again after a delete carries on from its
+            // last revision, so a revision read before the delete is stale.
+            let (third, _) = c.get_with_revision(&bob.uuid).unwrap();
+            c.delete(&bob.uuid).unwrap();
+            c.insert(bob.clone()).unwrap();
+            assert_eq!(c.revision(&bob.uuid), Some(3));
+            let res = c.update_if_revision(third, revision);
+            assert!(matches!(res, Err(Error::StaleRevision { .. })));
+            drop(c);
+
+            let c = Collection::<User>::new_arc(backend, Some(fp.clone())).unwrap();
+            let doc = Collection::read_guard(&c, &bob.uuid).unwrap().unwrap();
+            assert_eq!(doc.name, "bob");
+            assert_eq!(doc.revision(), 3);
+        }
+
+        // The document's own error is kept when it fails to load.
+        let fp = test_path("test_revisions_corrupt");
+        fs::create_dir_all(&fp).unwrap();
+        let json = r#"{"$meta":{"revision":1},"doc":{"uuid":"x","name":"bob"}}"#;
+        fs::write(fp.join("x.json"), json).unwrap();
+        let res = Collection::<User>::new(CollectionBackend::Dir, Some(fp));
+        let Err(Error::Serialize { source }) = res else {
+            panic!("expected a serialize error");
+        };
+        assert!(source.to_string().contains("UUID"));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut c = Collection::<User>::new(CollectionBackend::InMemory, None).unwrap();
+        let all = c.subscribe();
+        let bobs = c.subscribe_filtered(|u| u.name.starts_with("bob"));
+        let dropped = c.subscribe();
+        drop(dropped);
+
+        let mut bob = User::new("bob".to_string());
+        let amy = User::new("amy".to_string());
+        c.insert(bob.clone()).unwrap();
+        c.insert(amy.clone()).unwrap();
+        assert_eq!(c.subscribers.len(), 2);
+        assert!(c.insert(User::new("amy".to_string())).is_err());
+        let old = bob.clone();
+        bob.name = "robert".to_string();
+        c.update(bob.clone()).unwrap();
+        c.batch()
+            .delete(&amy.uuid)
+            .delete(&bob.uuid)
+            .commit()
+            .unwrap();
+
+        let events: Vec<ChangeEvent<User>> = all.try_iter().collect();
+        assert_eq!(events.len(), 5);
+        assert!(matches!(&events[1], ChangeEvent::Inserted { new } if new.name == "amy"));
+        assert!(matches!(
+            &events[2],
+            ChangeEvent::Updated { old, new } if old.name == "bob" && new.name == "robert"
+        ));
+        assert!(matches!(&events[4], ChangeEvent::Deleted { old } if old.name == "robert"));
+
+        let events: Vec<ChangeEvent<User>> = bobs.try_iter().collect();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], ChangeEvent::Inserted { new } if new.uuid == old.uuid));
+        assert_eq!(events[1].document().name, "robert");
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut c = Collection::<Account>::new(CollectionBackend::InMemory, None).unwrap();
+        let log = Arc::new(RwLock::new(vec![]));
+        c.before_insert(|a| {
+            a.email = a.email.to_lowercase();
+            Ok(())
+        });
+        c.before_update(|old, new| {
+            if old.email != new.email && new.email.ends_with("@old.com") {
+                return Err(Error::Rejected {
+                    reason: "old domain".to_string(),
+                });
+            }
+            Ok(())
+        });
+        c.before_delete(|a| {
+            if a.email.starts_with("admin") {
+                return Err(Error::Rejected {
+                    reason: "cannot delete admin".to_string(),
+                });
+            }
+            Ok(())
+        });
+        let l = log.clone();
+        c.after_insert(move |a| l.write().unwrap().push(format!("insert {}", a.email)));
+        let l = log.clone();
+        c.after_update(move |old, new| {
+            l.write()
+                .unwrap()
+                .push(format!("update {} {}", old.email, new.email))
+        });
+        let l = log.clone();
+        c.after_delete(move |a| l.write().unwrap().push(format!("delete {}", a.email)));
+
+        let admin = Account::new("Admin@E.g.com");
+        c.insert(admin.clone()).unwrap();
+        assert_eq!(
+            c.by_primary_key(&admin.uuid).unwrap().email,
+            "admin@e.g.com"
+        );
+        let res = c.insert(Account::new("ADMIN@e.g.com"));
+        assert!(matches!(res, Err(Error::UniqueViolation { .. })));
+
+        let mut bob = Account::new("bob@e.g.com");
+        c.batch().insert(bob.clone()).commit().unwrap();
+        bob.email = "bob@old.com".to_string();
+        assert!(matches!(c.update(bob.clone()), Err(Error::Rejected { .. })));
+        bob.email = "bob@new.com".to_string();
+        c.update(bob.clone()).unwrap();
+
+        assert!(matches!(c.delete(&admin.uuid), Err(Error::Rejected { .. })));
+        c.delete(&bob.uuid).unwrap();
+        assert_eq!(
+            *log.read().unwrap(),
+            vec![
+                "insert admin@e.g.com",
+                "insert bob@e.g.com",
+                "update bob@e.g.com bob@new.com",
+                "delete bob@new.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut c = Collection::<Account>::new(CollectionBackend::InMemory, None).unwrap();
+        c.insert(Account::new("")).unwrap();
+
+        c.enable_validation();
+        let mut ok = Account::new("a@e.g.com");
+        c.insert(ok.clone()).unwrap();
+        match c.insert(Account::new("")) {
+            Err(Error::Invalid { errors }) => {
+                assert_eq!(
+                    errors.field("email"),
+                    ["must not be empty", "must contain @"]
+                );
+                assert!(errors.field("uuid").is_empty());
+            }
+            res => panic!("expected validation errors, got {:?}", res),
+        }
+
+        ok.email = "nobody".to_string();
+        assert!(matches!(c.update(ok.clone()), Err(Error::Invalid { .. })));
+        let res = c
+            .batch()
+            .insert(Account::new("b@e.g.com"))
+            .insert(Account::new("c"))
+            .commit();
+        assert!(matches!(res, Err(Error::Invalid { .. })));
+        assert_eq!(c.len(), 2);
+    }
+
+    #[test]
+    fn test_history() {
+        let c = Collection::<User>::new(CollectionBackend::InMemory, None).unwrap();
+        assert!(matches!(
+            c.history(&Uuid::new_v4()),
+            Err(Error::HistoryDisabled)
+        ));
+
+        for (backend, fp) in persistent_paths("test_history") {
+            let tick = || {
+                std::thread::sleep(Duration::from_millis(2));
+                let now = chrono::Utc::now();
+                std::thread::sleep(Duration::from_millis(2));
+                now
+            };
+
+            let mut c = Collection::<User>::new(backend, Some(fp.clone())).unwrap();
+            c.enable_history().unwrap();
+            let before = tick();
+            let mut bob = User::new("bob".to_string());
+            c.insert(bob.clone()).unwrap();
+            let first = tick();
+            bob.name = "robert".to_string();
+            c.update(bob.clone()).unwrap();
+            let second = tick();
+            c.delete(&bob.uuid).unwrap();
+            let deleted = tick();
+
+            let mut c = Collection::<User>::new(backend, Some(fp.clone())).unwrap();
+            c.enable_history().unwrap();
+            let history = c.history(&bob.uuid).unwrap();
+            assert_eq!(history.len(), 2);
+            assert_eq!((history[0].revision, history[1].revision), (1, 2));
+            assert!(!history[0].deleted && history[1].deleted);
+            assert!(c.as_of(&bob.uuid, before).unwrap().is_none());
+            assert_eq!(c.as_of(&bob.uuid, first).unwrap().unwrap().name, "bob");
+            assert_eq!(c.as_of(&bob.uuid, second).unwrap().unwrap().name, "robert");
+            assert!(c.as_of(&bob.uuid, deleted).unwrap().is_none());
+
+            assert_eq!(c.restore(&bob.uuid, 1).unwrap(), 3);
+            assert_eq!(c.by_primary_key(&bob.uuid).unwrap().name, "bob");
+            assert_eq!(
+                c.as_of(&bob.uuid, chrono::Utc::now())
+                    .unwrap()
+                    .unwrap()
+                    .name,
+                "bob"
+            );
+            assert!(matches!(c.restore(&bob.uuid, 7), Err(Error::NotFound)));
+        }
+    }
+
+    #[test]
+    fn test_soft_delete() {
+        for (backend, fp) in persistent_paths("test_soft_delete") {
+            let mut c = Collection::<User>::new(backend, Some(fp.clone())).unwrap();
+            c.enable_soft_delete();
+            let bob = User::new("bob".to_string());
+            let alice = User::new("alice".to_string());
+            c.insert(bob.clone()).unwrap();
+            c.insert(alice.clone()).unwrap();
+            c.delete(&bob.uuid).unwrap();
+            c.batch().delete(&alice.uuid).commit().unwrap();
+            assert!(matches!(c.delete(&bob.uuid), Err(Error::NotFound)));
+            assert!(matches!(c.insert(bob.clone()), Err(Error::DuplicateKey)));
+
+            let mut c = Collection::<User>::new(backend, Some(fp.clone())).unwrap();
+            c.enable_soft_delete();
+            assert!(c.by_primary_key(&bob.uuid).is_none());
+            assert!(c.find(|u| u.name == "bob").is_none());
+            assert_eq!(c.query().count(), 0);
+            assert_eq!(c.query().with_deleted().count(), 2);
+            assert_eq!(c.with_deleted().filter(|_| true).len(), 2);
+            assert!(c.with_deleted().deleted_at(&bob.uuid).is_some());
+
+            c.undelete(&bob.uuid).unwrap();
+            assert_eq!(c.by_primary_key(&bob.uuid).unwrap().name, "bob");
+            assert!(c.with_deleted().deleted_at(&bob.uuid).is_none());
+            assert!(matches!(c.undelete(&bob.uuid), Err(Error::NotFound)));
+
+            assert_eq!(c.purge(chrono::Utc::now()).unwrap(), 1);
+            assert!(c.with_deleted().by_primary_key(&alice.uuid).is_none());
+            let c = Collection::<User>::new(backend, Some(fp.clone())).unwrap();
+            assert_eq!(c.with_deleted().iter().count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_expiry() {
+        let fp = test_path("test_expiry");
+
+        let now = chrono::Utc::now();
+        let expiring = |id, at| Ticket {
+            expires_at: Some(at),
+            ..Ticket::new(id, "session")
+        };
+        let mut c = Collection::<Ticket>::new(CollectionBackend::Dir, Some(fp.clone())).unwrap();
+        let deleted = Arc::new(AtomicUsize::new(0));
+        let counter = deleted.clone();
+        c.after_delete(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        for (id, expires_at) in [
+            (1, now - chrono::TimeDelta::hours(1)),
+            (2, now + chrono::TimeDelta::hours(1)),
+        ] {
+            c.insert(expiring(id, expires_at)).unwrap();
+        }
+        assert!(c.by_primary_key(&1).is_none());
+        assert_eq!(c.query().count(), 1);
+        assert_eq!(c.expires_at(&2), Some(now + chrono::TimeDelta::hours(1)));
+        assert_eq!(c.sweep_expired().unwrap(), 1);
+        assert_eq!(deleted.load(Ordering::SeqCst), 1);
+        let c = Collection::<Ticket>::new(CollectionBackend::Dir, Some(fp.clone())).unwrap();
+        assert_eq!(c.with_deleted().iter().count(), 1);
+
+        // Writes treat expired documents as absent.
+        let mut c = Collection::<Account>::new(CollectionBackend::InMemory, None).unwrap();
+        c.set_ttl(Duration::ZERO);
+        let bob = Account::new("bob@e.g.com");
+        c.insert(bob.clone()).unwrap();
+        assert!(matches!(
+            c.update_existing(bob.clone()),
+            Err(Error::NotFound)
+        ));
+        c.insert(bob.clone()).unwrap();
+        let other = Account::new("bob@e.g.com");
+        c.insert(other.clone()).unwrap();
+        assert_eq!(c.with_deleted().iter().count(), 1);
+        assert!(c.with_deleted().by_primary_key(&other.uuid).is_some());
+
+        // Hooks can keep a document and soft deleted ones are left for `purge`.
+        let mut c = Collection::<Ticket>::new(CollectionBackend::InMemory, None).unwrap();
+        c.enable_soft_delete();
+        c.before_delete(|t| match t.id {
+            1 => Err(Error::Rejected {
+                reason: "still in use".to_string(),
+            }),
+            _ => Ok(()),
+        });
+        for id in [1, 2] {
+            let expires_at = now - chrono::TimeDelta::hours(1);
+            c.insert(expiring(id, expires_at)).unwrap();
+        }
+        assert_eq!(c.sweep_expired().unwrap(), 1);
+        assert!(c.with_deleted().deleted_at(&1).is_none());
+        assert!(c.with_deleted().deleted_at(&2).is_some());
+        assert_eq!(c.sweep_expired().unwrap(), 0);
+
+        // Expired documents holding the keys of a write are deleted
+        // outright, keys and all, unless a hook keeps them.
+        c.insert(Ticket::new(2, "renewed")).unwrap();
+        assert_eq!(c.by_primary_key(&2).unwrap().title, "renewed");
+        assert!(matches!(
+            c.insert(Ticket::new(1, "renewed")),
+            Err(Error::DuplicateKey)
+        ));
+
+        // The keys are those set by the before hooks.
+        let mut c = Collection::<Account>::new(CollectionBackend::InMemory, None).unwrap();
+        c.enable_soft_delete();
+        c.set_ttl(Duration::ZERO);
+        c.before_insert(|account| {
+            account.email = account.email.to_lowercase();
+            Ok(())
+        });
+        c.insert(Account::new("bob@e.g.com")).unwrap();
+        let other = Account::new("BOB@e.g.com");
+        c.insert(other.clone()).unwrap();
+        assert_eq!(c.with_deleted().iter().count(), 1);
+        assert!(c.with_deleted().by_primary_key(&other.uuid).is_some());
+
+        let c = Collection::<Ticket>::new_arc(CollectionBackend::InMemory, None).unwrap();
+        let expires_at = now - chrono::TimeDelta::hours(1);
+        c.write().unwrap().insert(expiring(1, expires_at)).unwrap();
+        let sweeper = Collection::spawn_sweeper(&c, Duration::from_millis(1));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !c.read().unwrap().documents.is_empty() {
+            assert!(Instant::now() < deadline, "sweeper did not run");
+            std::thread::sleep(Duration::from_millis(1));
         }
+        drop(c);
+        sweeper.join().unwrap();
     }
 }
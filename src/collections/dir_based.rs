@@ -1,69 +1,186 @@
-use std::fmt::Debug;
+use std::collections::HashSet;
+use std::fmt::{Display, Write as _};
 use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 use serde::{de::DeserializeOwned, Serialize};
-use uuid::Uuid;
 
-use crate::Document;
+use crate::{Document, DocumentMeta, Error};
 
-use super::collection::Collection;
+use super::backend::{sync_dir, StorageBackend};
+use super::collection::Durability;
+use super::meta;
 
-impl<T> Collection<T>
-where
-    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
-{
-    pub fn load_structs_from_dir(&mut self) {
-        if self.path.is_none() {
-            return;
-        }
-        let path = self.path.as_ref().unwrap();
-        let paths = fs::read_dir(path);
-        if paths.is_err() {
-            dbg!("Error reading ReadDir");
-            return;
+const COUNTER: &str = "counter";
+const HISTORY: &str = "history.jsonl";
+
+/// Stores each document in its own `<key>.json` file within a directory.
+/// Characters in the key other than lowercase ASCII letters, digits,
+/// `-` and `_` are percent-encoded so any key makes a safe file name.
+/// Documents are written to a temporary file that is renamed into place.
+/// The file is synced before the rename, so a crash never leaves a
+/// partially written document, unless the durability is `Durability::None`.
+/// With `Durability::FlushOnEachWrite` the directory is synced after the
+/// rename. Otherwise it is synced by `flush`, along with any files
+/// written without being synced.
+/// The key generator counter is kept in a `counter` file and any
+/// history in a `history.jsonl` file alongside the documents.
+#[derive(Debug)]
+pub struct DirBackend {
+    pub path: PathBuf,
+    durability: Durability,
+    /// Files written since the last `flush` without being synced.
+    unsynced: HashSet<PathBuf>,
+    /// Files have been renamed or removed since the directory was last synced.
+    dirty: bool,
+}
+
+impl DirBackend {
+    pub fn new(path: PathBuf) -> Result<Self, Error> {
+        fs::create_dir_all(&path)?;
+        Ok(DirBackend {
+            path,
+            durability: Durability::default(),
+            unsynced: HashSet::new(),
+            dirty: false,
+        })
+    }
+
+    fn document_path(&self, pk: &impl Display) -> PathBuf {
+        self.path
+            .join(format!("{}.json", escape_key(&pk.to_string())))
+    }
+
+    fn write_to_dir<T: Document<T> + Serialize>(
+        &mut self,
+        doc: &T,
+        meta: &DocumentMeta,
+    ) -> Result<(), Error> {
+        let json = meta::to_json(doc, meta)?;
+        let path = self.document_path(&doc.primary_key());
+        self.write_file(&path, &json)
+    }
+
+    /// Write `contents` to a temporary file and rename it to `path`,
+    /// syncing them as the durability requires.
+    fn write_file(&mut self, path: &Path, contents: &str) -> Result<(), Error> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut f = fs::File::create(&tmp_path)?;
+        f.write_all(contents.as_bytes())?;
+        match self.durability {
+            Durability::FlushOnEachWrite => {
+                f.sync_all()?;
+                fs::rename(&tmp_path, path)?;
+                return sync_dir(path);
+            }
+            Durability::None => {
+                fs::rename(&tmp_path, path)?;
+                self.unsynced.insert(path.to_owned());
+            }
+            Durability::GroupCommit { .. } | Durability::Manual => {
+                f.sync_data()?;
+                fs::rename(&tmp_path, path)?;
+            }
         }
-        let paths = paths.unwrap();
-        for path in paths {
-            if path.is_err() {
-                dbg!("Error reading DirEntry");
-                continue;
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+/// Percent-encode a key for use as a file name. Uppercase letters are
+/// encoded too so keys differing only in case do not collide on
+/// case-insensitive file systems.
+fn escape_key(key: &str) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => escaped.push(byte as char),
+            _ => {
+                let _ = write!(escaped, "%{:02X}", byte);
             }
-            let path = path.unwrap().path();
-            if path.extension().unwrap() != "json" {
+        }
+    }
+    escaped
+}
+
+impl<T> StorageBackend<T> for DirBackend
+where
+    T: Document<T> + Serialize + DeserializeOwned,
+{
+    fn load_all(&mut self) -> Result<Vec<(T, DocumentMeta)>, Error> {
+        let mut docs = vec![];
+        for path in fs::read_dir(&self.path)? {
+            let path = path?.path();
+            // Left behind by a write that did not complete.
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                fs::remove_file(&path)?;
                 continue;
             }
-            let f = fs::OpenOptions::new().read(true).open(&path);
-            if f.is_err() {
-                dbg!("Error opening {}", &path);
+            if path.extension().is_none_or(|ext| ext != "json") {
                 continue;
             }
-            let f = f.unwrap();
-            // Could better handle serde parsing errors.
-            let doc: T = serde_json::from_reader(f).unwrap();
-            self.documents.insert(doc.primary_key(), doc);
+            docs.push(meta::from_json(&fs::read_to_string(&path)?)?);
         }
+        Ok(docs)
     }
 
-    pub fn write_to_dir(&self, doc: &T) -> Result<(), &str> {
-        let json = serde_json::to_string(&doc).unwrap();
-        let path = self.path.clone().unwrap();
-        let file_name = format!("{}.json", doc.primary_key());
-        let path = path.join(file_name);
-        let err = fs::write(path, json);
-        if err.is_err() {
-            return Err("Error writing to disk.");
+    fn persist_insert(&mut self, doc: &T, meta: &DocumentMeta) -> Result<(), Error> {
+        self.write_to_dir(doc, meta)
+    }
+
+    fn persist_update(&mut self, doc: &T, meta: &DocumentMeta) -> Result<(), Error> {
+        self.write_to_dir(doc, meta)
+    }
+
+    fn persist_delete(&mut self, pk: &T::Key) -> Result<(), Error> {
+        let path = self.document_path(pk);
+        fs::remove_file(&path)?;
+        self.unsynced.remove(&path);
+        if self.durability == Durability::FlushOnEachWrite {
+            return sync_dir(&path);
         }
-        return Ok(());
+        self.dirty = true;
+        Ok(())
     }
 
-    pub fn remove_from_dir(&self, pk: &Uuid) -> Result<(), &str> {
-        // Delete file
-        let path = self.path.clone().unwrap();
-        let file_name = format!("{}.json", pk);
-        let path = path.join(file_name);
-        let err = fs::remove_file(path);
-        if err.is_err() {
-            return Err("Error removing file from dir (it may not exist)");
+    fn history_path(&self) -> Option<PathBuf> {
+        Some(self.path.join(HISTORY))
+    }
+
+    fn load_counter(&mut self) -> Result<Option<u64>, Error> {
+        let path = self.path.join(COUNTER);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let counter = fs::read_to_string(&path)?;
+        let counter = counter.trim().parse().map_err(|_| Error::InvalidFile {
+            reason: format!("invalid counter {:?}", counter),
+        })?;
+        Ok(Some(counter))
+    }
+
+    fn persist_counter(&mut self, counter: u64) -> Result<(), Error> {
+        let path = self.path.join(COUNTER);
+        self.write_file(&path, &counter.to_string())
+    }
+
+    fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        for path in self.unsynced.drain() {
+            match fs::File::open(&path) {
+                Ok(f) => f.sync_all()?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if self.dirty {
+            fs::File::open(&self.path)?.sync_all()?;
+            self.dirty = false;
         }
         Ok(())
     }
@@ -0,0 +1,136 @@
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Document, DocumentMeta, Error};
+
+use super::collection::Collection;
+use super::wal::WalOp;
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Expire documents `ttl` after they were last written. Documents
+    /// can also set their own expiry with `Document::expires_at`.
+    /// A `ttl` too long to represent never expires.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = TimeDelta::from_std(ttl).ok();
+    }
+
+    /// When a document expires, or `None` if it never does.
+    pub fn expires_at(&self, pk: &T::Key) -> Option<DateTime<Utc>> {
+        self.expiry(self.documents.get(pk)?, self.meta.get(pk))
+    }
+
+    /// Delete the expired documents holding the primary or unique keys
+    /// of `doc` so that writing it treats them as absent. They are deleted
+    /// outright even with soft delete enabled, as a soft deleted document
+    /// keeps its keys.
+    pub(super) fn sweep_conflicts(&mut self, doc: &T) -> Result<(), Error> {
+        let now = Utc::now();
+        let mut pks: Vec<T::Key> = doc
+            .unique_keys()
+            .into_iter()
+            .filter_map(|(name, value)| self.unique_index.owner(name, &value).cloned())
+            .chain(Some(doc.primary_key()))
+            .filter(|pk| {
+                self.documents
+                    .get(pk)
+                    .is_some_and(|doc| self.expired_at(doc, self.meta.get(pk), now))
+            })
+            .collect();
+        pks.sort();
+        pks.dedup();
+        self.sweep(pks.into_iter().map(WalOp::Delete).collect())?;
+        Ok(())
+    }
+
+    /// Documents that have not expired by `now`, which are the
+    /// ones a write is checked against.
+    pub(super) fn unexpired(&self, now: DateTime<Utc>) -> impl Iterator<Item = &T> {
+        self.documents
+            .iter()
+            .filter(move |(pk, doc)| !self.expired_at(doc, self.meta.get(pk), now))
+            .map(|(_, doc)| doc)
+    }
+
+    /// Whether `doc`, stored with `meta`, had expired by `now`.
+    pub(super) fn expired_at(
+        &self,
+        doc: &T,
+        meta: Option<&DocumentMeta>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        self.expiry(doc, meta).is_some_and(|at| at <= now)
+    }
+
+    /// Whether a document that is not soft deleted had expired by `now`.
+    fn is_expired(&self, pk: &T::Key, now: DateTime<Utc>) -> bool {
+        let meta = self.meta.get(pk);
+        meta.is_none_or(|meta| meta.deleted_at.is_none())
+            && self
+                .documents
+                .get(pk)
+                .is_some_and(|doc| self.expired_at(doc, meta, now))
+    }
+
+    /// Apply the deletes, skipping any that a `before_delete` hook
+    /// rejects. Returns the number deleted.
+    fn sweep(&mut self, ops: Vec<WalOp<T>>) -> Result<usize, Error> {
+        let ops: Vec<WalOp<T>> = ops
+            .into_iter()
+            .filter_map(|op| self.run_before_hooks(op).ok())
+            .collect();
+        let swept = ops.len();
+        if swept > 0 {
+            self.commit(ops)?;
+        }
+        Ok(swept)
+    }
+
+    fn expiry(&self, doc: &T, meta: Option<&DocumentMeta>) -> Option<DateTime<Utc>> {
+        let from_ttl = self
+            .ttl
+            .and_then(|ttl| meta?.written_at?.checked_add_signed(ttl));
+        match (from_ttl, doc.expires_at()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Delete every expired document as `delete` would, soft deleting them
+    /// if soft delete is enabled. Documents that a `before_delete` hook
+    /// rejects are kept. Returns the number of documents deleted.
+    pub fn sweep_expired(&mut self) -> Result<usize, Error> {
+        let now = Utc::now();
+        let ops: Vec<WalOp<T>> = self
+            .documents
+            .keys()
+            .filter(|pk| self.is_expired(pk, now))
+            .map(|pk| self.delete_op(pk.clone()))
+            .collect();
+        self.sweep(ops)
+    }
+
+    /// Call `sweep_expired` on a collection shared through `new_arc`
+    /// every `interval`. The thread stops once the collection is dropped.
+    pub fn spawn_sweeper(shared: &Arc<RwLock<Self>>, interval: Duration) -> JoinHandle<()> {
+        let shared = Arc::downgrade(shared);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(shared) = shared.upgrade() else {
+                return;
+            };
+            let Ok(mut collection) = shared.write() else {
+                return;
+            };
+            // A failed sweep is retried on the next interval.
+            let _ = collection.sweep_expired();
+        })
+    }
+}
@@ -1,166 +1,394 @@
-use std::fs;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{BufRead, BufReader, Write};
 use std::os::unix::fs::FileExt;
-use std::{fmt::Debug, io::BufRead, io::BufReader};
+use std::path::PathBuf;
 
 use chrono::Utc;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::Document;
+use crate::{Document, DocumentMeta, Error};
 
-use super::collection::Collection;
+use super::backend::{sync_dir, CompactionStats, StorageBackend};
+use super::meta;
 
-impl<T> Collection<T>
+const MAGIC: &str = "struvedb";
+const FORMAT_VERSION: u32 = 1;
+/// Width of the header line, excluding the newline.
+const HEADER_LENGTH: usize = 127;
+
+/// The first line of a collection file. Records the row width
+/// so the file can be reopened after a resize.
+#[derive(Debug, Serialize, Deserialize)]
+struct FileHeader {
+    magic: String,
+    version: u32,
+    max_byte_length: usize,
+    byte_length_increment: usize,
+    /// The key generator counter. Missing from files written before it was added.
+    #[serde(default)]
+    counter: Option<u64>,
+}
+
+/// Stores every document in a single file, one document per line.
+/// Each line is padded with spaces to `max_byte_length` so a document
+/// can be updated in place by writing at the row offset.
+/// Deleted documents leave a blank row (tombstone) that is
+/// reused by the next insert.
+#[derive(Debug)]
+pub struct FileBackend<K> {
+    pub path: PathBuf,
+    pub max_byte_length: usize,
+    pub byte_length_increment: usize,
+    file: File,
+    /// Offset of the first row. Zero for files written before
+    /// the header was introduced, until they are rewritten.
+    data_offset: usize,
+    /// The row (slot) each document is stored in.
+    slots: HashMap<K, usize>,
+    /// Tombstoned slots available for reuse.
+    free_slots: BTreeSet<usize>,
+    /// Number of rows in the file, including tombstones.
+    slot_count: usize,
+    /// Compact the file after a delete once the share of rows that
+    /// are tombstones exceeds this ratio. `None` disables it.
+    pub auto_compact_ratio: Option<f64>,
+    /// The key generator counter, kept in the header.
+    counter: Option<u64>,
+}
+
+impl<K> FileBackend<K>
 where
-    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+    K: Hash + Eq + Clone,
 {
-    pub fn load_structs_from_file(&mut self) {
-        if self.path.is_none() {
-            return;
-        }
-        let path = self.path.as_ref().unwrap();
-        let f = fs::OpenOptions::new()
+    pub fn new(path: PathBuf) -> Result<Self, Error> {
+        let file = fs::OpenOptions::new()
             .create(true)
+            .truncate(false)
             .write(true)
             .read(true)
-            .open(path);
-        if f.is_err() {
-            println!("Error opening {:?}", path);
-            return;
-        }
-        let f = f.unwrap();
-        let reader = BufReader::new(&f);
-        for line in reader.lines() {
-            let line = line.unwrap();
-            let document = serde_json::from_str(&line.trim());
-            if document.is_err() {
-                break;
+            .open(&path)?;
+        let mut backend = FileBackend {
+            path,
+            max_byte_length: 128,
+            byte_length_increment: 64,
+            file,
+            data_offset: HEADER_LENGTH + 1,
+            slots: HashMap::new(),
+            free_slots: BTreeSet::new(),
+            slot_count: 0,
+            auto_compact_ratio: None,
+            counter: None,
+        };
+        if backend.file.metadata()?.len() == 0 {
+            backend.write_header()?;
+        } else {
+            backend.read_header()?;
+        }
+        Ok(backend)
+    }
+
+    /// Number of tombstoned rows in the file.
+    pub fn tombstones(&self) -> usize {
+        self.free_slots.len()
+    }
+
+    fn header_row(&self) -> Result<String, Error> {
+        let header = FileHeader {
+            magic: MAGIC.to_string(),
+            version: FORMAT_VERSION,
+            max_byte_length: self.max_byte_length,
+            byte_length_increment: self.byte_length_increment,
+            counter: self.counter,
+        };
+        let json = serde_json::to_string(&header)?;
+        Ok(format!("{:width$}\n", json, width = HEADER_LENGTH))
+    }
+
+    fn write_header(&self) -> Result<(), Error> {
+        self.file.write_at(self.header_row()?.as_bytes(), 0)?;
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> Result<(), Error> {
+        let mut first_line = String::new();
+        BufReader::new(&self.file).read_line(&mut first_line)?;
+        let first_line = first_line.trim_end_matches('\n');
+        match serde_json::from_str::<FileHeader>(first_line.trim_end()) {
+            Ok(header) if header.magic == MAGIC => {
+                if header.version != FORMAT_VERSION {
+                    return Err(Error::InvalidFile {
+                        reason: format!("unsupported format version {}", header.version),
+                    });
+                }
+                if first_line.len() != HEADER_LENGTH {
+                    return Err(Error::InvalidFile {
+                        reason: "header has the wrong length".to_string(),
+                    });
+                }
+                self.max_byte_length = header.max_byte_length;
+                self.byte_length_increment = header.byte_length_increment;
+                self.counter = header.counter;
             }
-            let document: T = document.unwrap();
-            self.documents.insert(document.primary_key(), document);
-        }
-        self.file = Some(f);
-    }
-
-    pub fn write_new_document_to_file(&mut self, doc: &T) -> Result<(), &str> {
-        let json = serde_json::to_string(&doc);
-        if json.is_err() {
-            return Err("Error turning struct into JSON");
-        }
-        let json = json.unwrap();
-        let byte_length = json.len();
-        if byte_length > self.max_byte_length {
-            let div = (byte_length / self.byte_length_increment) + 1;
-            self.max_byte_length = self.byte_length_increment * div;
-            println!(
-                "{} > DB Resize New Byte Length: {}",
-                Utc::now(),
-                self.max_byte_length
-            );
-            let resize_success = self.resize_db();
-            if resize_success.is_err() {
-                return Err("Failed to resize DB");
+            _ => {
+                // A file without a header. Take the row width from the
+                // first row and add the header when the rows are loaded.
+                self.data_offset = 0;
+                self.max_byte_length = first_line.len();
             }
         }
-        let padded_string = format!("{:width$}\n", json, width = self.max_byte_length);
-        let offset: u64 = (self.documents.len() * (self.max_byte_length + 1))
+        Ok(())
+    }
+
+    fn row_offset(&self, idx: usize) -> u64 {
+        (self.data_offset + idx * (self.max_byte_length + 1))
             .try_into()
-            .unwrap();
+            .unwrap()
+    }
 
-        let file = self.file.as_ref().unwrap();
-        let write_success = file.write_at(padded_string.as_bytes(), offset);
-        if write_success.is_err() {
-            return Err("Failed to write");
+    /// Pad a row with spaces to `max_byte_length` bytes. `format!` pads
+    /// by characters, which overflows the row for non-ASCII documents.
+    fn pad_row(&self, json: &str) -> Result<String, Error> {
+        if json.len() > self.max_byte_length {
+            return Err(Error::RowTooLarge {
+                length: json.len(),
+                max_length: self.max_byte_length,
+            });
         }
+        Ok(format!(
+            "{}{}\n",
+            json,
+            " ".repeat(self.max_byte_length - json.len())
+        ))
+    }
 
+    fn write_row(&self, idx: usize, json: &str) -> Result<(), Error> {
+        let padded_string = self.pad_row(json)?;
+        self.file
+            .write_at(padded_string.as_bytes(), self.row_offset(idx))?;
         Ok(())
     }
 
-    pub fn write_updated_document_to_file(&mut self, doc: &T) -> Result<(), &str> {
-        let json = serde_json::to_string(&doc);
-        if json.is_err() {
-            return Err("Error turning struct into JSON");
+    /// Read `count` rows from the file, checking each one
+    /// is exactly `max_byte_length` wide. Tombstones are empty strings.
+    fn read_rows(&self, count: usize) -> Result<Vec<String>, Error> {
+        let mut rows = Vec::with_capacity(count);
+        let mut buf = vec![0; self.max_byte_length + 1];
+        for idx in 0..count {
+            self.file.read_exact_at(&mut buf, self.row_offset(idx))?;
+            if buf[self.max_byte_length] != b'\n' || buf[..self.max_byte_length].contains(&b'\n') {
+                return Err(Error::InvalidFile {
+                    reason: format!("row {} is not {} bytes wide", idx, self.max_byte_length),
+                });
+            }
+            rows.push(String::from_utf8_lossy(&buf).trim_end().to_string());
         }
-        let json = json.unwrap();
-        let byte_length = json.len();
-        if byte_length > self.max_byte_length {
-            let div = (byte_length / self.byte_length_increment) + 1;
-            self.max_byte_length = self.byte_length_increment * div;
-            let resize_success = self.resize_db();
-            if resize_success.is_err() {
-                return Err("Failed to resize DB");
+        Ok(rows)
+    }
+
+    /// Grow the row width so that `byte_length` fits, rewriting the file.
+    fn grow_rows(&mut self, byte_length: usize) -> Result<(), Error> {
+        if byte_length <= self.max_byte_length {
+            return Ok(());
+        }
+        let rows = self.read_rows(self.slot_count)?;
+        let old_max_byte_length = self.max_byte_length;
+        let div = (byte_length / self.byte_length_increment) + 1;
+        self.max_byte_length = self.byte_length_increment * div;
+        println!(
+            "{} > DB Resize New Byte Length: {}",
+            Utc::now(),
+            self.max_byte_length
+        );
+        if let Err(err) = self.rewrite_file(&rows) {
+            self.max_byte_length = old_max_byte_length;
+            return Err(err);
+        }
+        sync_dir(&self.path)
+    }
+
+    /// Rewrite the file without tombstones at the smallest row width that
+    /// fits every document.
+    pub fn compact(&mut self) -> Result<CompactionStats, Error> {
+        let bytes_before = self.file.metadata()?.len();
+        let rows = self.read_rows(self.slot_count)?;
+        let mut pks = vec![None; self.slot_count];
+        for (pk, slot) in self.slots.iter() {
+            pks[*slot] = Some(pk.clone());
+        }
+
+        let mut slots = HashMap::with_capacity(self.slots.len());
+        let mut compacted = Vec::with_capacity(self.slots.len());
+        let mut rows_moved = 0;
+        for (slot, (row, pk)) in rows.into_iter().zip(pks).enumerate() {
+            let Some(pk) = pk else { continue };
+            if slots.len() != slot {
+                rows_moved += 1;
             }
+            slots.insert(pk, slots.len());
+            compacted.push(row);
         }
 
-        let padded_string = format!("{:width$}\n", json, width = self.max_byte_length);
-        // Write right location in the file
-        let idx = self.documents.get_index_of(&doc.primary_key());
-        if idx.is_none() {
-            return Err("Row idx cannot be found");
+        let old_max_byte_length = self.max_byte_length;
+        self.max_byte_length = compacted
+            .iter()
+            .map(|row| row.len())
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        if let Err(err) = self.rewrite_file(&compacted) {
+            self.max_byte_length = old_max_byte_length;
+            return Err(err);
         }
-        let idx = idx.unwrap();
-        let offset: u64 = (idx * (self.max_byte_length + 1)).try_into().unwrap();
-        let file = self.file.as_ref().unwrap();
-        let write_success = file.write_at(padded_string.as_bytes(), offset);
-        if write_success.is_err() {
-            return Err("Failed to write");
+        self.slot_count = slots.len();
+        self.slots = slots;
+        self.free_slots.clear();
+        sync_dir(&self.path)?;
+
+        Ok(CompactionStats {
+            bytes_before,
+            bytes_after: self.file.metadata()?.len(),
+            rows_moved,
+        })
+    }
+
+    /// Replace the file with the header and the given rows, keeping
+    /// every row in its slot. The new file is written alongside the
+    /// original, synced and then renamed over it so a crash leaves
+    /// either the old or the new file in place. The file is in use once
+    /// this returns, so callers update their state before syncing the
+    /// directory with `sync_dir`.
+    fn rewrite_file(&mut self, rows: &[String]) -> Result<(), Error> {
+        let mut contents = self.header_row()?;
+        for json in rows.iter() {
+            contents.push_str(&self.pad_row(json)?);
         }
 
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let written = fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .read(true)
+            .open(&tmp_path)
+            .and_then(|mut tmp| {
+                tmp.write_all(contents.as_bytes())?;
+                tmp.sync_all()?;
+                fs::rename(&tmp_path, &self.path)?;
+                Ok(tmp)
+            });
+        match written {
+            Ok(tmp) => self.file = tmp,
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err.into());
+            }
+        }
+        self.data_offset = HEADER_LENGTH + 1;
         Ok(())
     }
+}
 
-    pub fn resize_db(&self) -> Result<(), &str> {
-        let file = self.file.as_ref().unwrap();
-        let cleared = file.set_len(0);
-        if cleared.is_err() {
-            return Err("Failed to clear contents of DB.");
+impl<T> StorageBackend<T> for FileBackend<T::Key>
+where
+    T: Document<T> + Serialize + DeserializeOwned,
+{
+    fn load_all(&mut self) -> Result<Vec<(T, DocumentMeta)>, Error> {
+        let file_length = self.file.metadata()?.len() as usize;
+        let data_length = file_length.saturating_sub(self.data_offset);
+        if file_length < self.data_offset || !data_length.is_multiple_of(self.max_byte_length + 1) {
+            return Err(Error::InvalidFile {
+                reason: format!(
+                    "file does not divide into rows of {} bytes",
+                    self.max_byte_length
+                ),
+            });
         }
-        for (idx, doc) in self.documents.iter().enumerate() {
-            let string = serde_json::to_string(&doc);
-            if string.is_err() {
-                return Err("Error turning struct into JSON");
-            }
-            let string = string.unwrap();
-            let byte_length = string.len();
-            if byte_length > self.max_byte_length {
-                return Err("Struct is to large");
-            }
-            let padded_string = format!("{:width$}\n", string, width = self.max_byte_length);
-            let offset: u64 = (idx * (self.max_byte_length + 1)).try_into().unwrap();
-            let file = self.file.as_ref().unwrap();
-            let write_success = file.write_at(padded_string.as_bytes(), offset);
-            if write_success.is_err() {
-                return Err("Failed to write");
+        let rows = self.read_rows(data_length / (self.max_byte_length + 1))?;
+        let mut docs = vec![];
+        for (slot, row) in rows.iter().enumerate() {
+            if row.is_empty() {
+                self.free_slots.insert(slot);
+                continue;
             }
+            let (document, meta): (T, DocumentMeta) = meta::from_json(row)?;
+            self.slots.insert(document.primary_key(), slot);
+            docs.push((document, meta));
         }
-        Ok(())
+        self.slot_count = rows.len();
+        if self.data_offset == 0 {
+            self.rewrite_file(&rows)?;
+            sync_dir(&self.path)?;
+        }
+        Ok(docs)
     }
 
-    pub fn rewrite_file(&self) -> Result<(), &str> {
-        // Clear and re-populate the DB
-        let file = self.file.as_ref().unwrap();
-        let cleared = file.set_len(0);
-        if cleared.is_err() {
-            return Err("Failed to clear contents of DB.");
+    fn persist_insert(&mut self, doc: &T, meta: &DocumentMeta) -> Result<(), Error> {
+        let json = meta::to_json(doc, meta)?;
+        self.grow_rows(json.len())?;
+        let slot = self.free_slots.first().copied().unwrap_or(self.slot_count);
+        self.write_row(slot, &json)?;
+        if !self.free_slots.remove(&slot) {
+            self.slot_count += 1;
         }
+        self.slots.insert(doc.primary_key(), slot);
+        Ok(())
+    }
 
-        for (idx, doc) in self.documents.iter().enumerate() {
-            let json = serde_json::to_string(&doc);
-            if json.is_err() {
-                return Err("Error turning struct into JSON");
+    fn persist_update(&mut self, doc: &T, meta: &DocumentMeta) -> Result<(), Error> {
+        match self.slots.get(&doc.primary_key()) {
+            Some(&slot) => {
+                let json = meta::to_json(doc, meta)?;
+                self.grow_rows(json.len())?;
+                self.write_row(slot, &json)
             }
-            let json = json.unwrap();
-            let byte_length = json.len();
-            if byte_length > self.max_byte_length {
-                return Err("Struct is to large");
-            }
-            let padded_string = format!("{:width$}\n", json, width = self.max_byte_length);
-            let offset: u64 = (idx * (self.max_byte_length + 1)).try_into().unwrap();
-            let write_success = file.write_at(padded_string.as_bytes(), offset);
-            if write_success.is_err() {
-                return Err("Failed to write");
+            None => self.persist_insert(doc, meta),
+        }
+    }
+
+    fn persist_delete(&mut self, pk: &T::Key) -> Result<(), Error> {
+        let slot = *self.slots.get(pk).ok_or(Error::NotFound)?;
+        self.write_row(slot, "")?;
+        self.slots.remove(pk);
+        self.free_slots.insert(slot);
+        if let Some(ratio) = self.auto_compact_ratio {
+            if self.tombstones() as f64 / self.slot_count as f64 > ratio {
+                // The delete has been written and a failed compaction
+                // leaves the file as it was, so it is retried on the
+                // next delete rather than failing this one.
+                let _ = self.compact();
             }
         }
+        Ok(())
+    }
 
+    fn flush(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
         Ok(())
     }
+
+    fn compact(&mut self) -> Result<CompactionStats, Error> {
+        FileBackend::compact(self)
+    }
+
+    fn history_path(&self) -> Option<PathBuf> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".history");
+        Some(path.into())
+    }
+
+    fn load_counter(&mut self) -> Result<Option<u64>, Error> {
+        Ok(self.counter)
+    }
+
+    fn persist_counter(&mut self, counter: u64) -> Result<(), Error> {
+        self.counter = Some(counter);
+        if self.data_offset == 0 {
+            // The header is added when the rows are loaded.
+            return Ok(());
+        }
+        self.write_header()
+    }
 }
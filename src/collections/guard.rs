@@ -0,0 +1,71 @@
+use std::fmt::Debug;
+use std::ops::Deref;
+use std::sync::{RwLock, RwLockReadGuard};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Document, Error};
+
+use super::collection::Collection;
+
+/// A document borrowed from a shared collection. The collection
+/// stays read locked until the guard is dropped, so hold it
+/// only as long as needed as writers wait on it.
+pub struct DocumentGuard<'a, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    guard: RwLockReadGuard<'a, Collection<T>>,
+    /// Position of the document in `documents`, which cannot
+    /// change while the lock is held.
+    position: usize,
+}
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Borrow a document from a collection shared through `new_arc`
+    /// without cloning it. Returns `None` if there is no document with the key.
+    pub fn read_guard<'a>(
+        shared: &'a RwLock<Self>,
+        pk: &T::Key,
+    ) -> Result<Option<DocumentGuard<'a, T>>, Error> {
+        let guard = shared.read().map_err(|_| Error::LockPoisoned)?;
+        Ok(guard
+            .documents
+            .get_index_of(pk)
+            .filter(|_| guard.is_visible(pk))
+            .map(|position| DocumentGuard { guard, position }))
+    }
+}
+
+impl<T> DocumentGuard<'_, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// The revision of the document.
+    pub fn revision(&self) -> u64 {
+        self.guard.meta[&self.primary_key()].revision
+    }
+}
+
+impl<T> Deref for DocumentGuard<'_, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard.documents[self.position]
+    }
+}
+
+impl<T> Debug for DocumentGuard<'_, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
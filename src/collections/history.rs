@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{Document, Error};
+
+use super::changes::Change;
+use super::collection::Collection;
+
+/// A version of a document that was replaced by an update or removed by a delete.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry<T> {
+    pub revision: u64,
+    /// When this version was written. `None` for versions
+    /// persisted before write times were recorded.
+    pub written_at: Option<DateTime<Utc>>,
+    /// When this version was replaced or deleted.
+    pub replaced_at: DateTime<Utc>,
+    /// Whether this version was removed by a delete rather than replaced.
+    pub deleted: bool,
+    pub document: T,
+}
+
+impl<T> HistoryEntry<T> {
+    /// Whether this was the current version at `at`.
+    fn current_at(&self, at: DateTime<Utc>) -> bool {
+        self.written_at.is_none_or(|written_at| written_at <= at) && at < self.replaced_at
+    }
+}
+
+/// The prior versions of every document, appended to a
+/// JSON lines file alongside the backend when it has a path.
+pub(super) struct History<T: Document<T>> {
+    file: Option<File>,
+    entries: HashMap<T::Key, Vec<HistoryEntry<T>>>,
+}
+
+impl<T> History<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned,
+{
+    fn open(path: Option<PathBuf>) -> Result<Self, Error> {
+        let mut history = History {
+            file: None,
+            entries: HashMap::new(),
+        };
+        let Some(path) = path else {
+            return Ok(history);
+        };
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        for line in BufReader::new(&file).lines() {
+            // A final line only partly written before a crash is ignored.
+            let Ok(entry) = serde_json::from_str::<HistoryEntry<T>>(&line?) else {
+                break;
+            };
+            history.push(entry);
+        }
+        history.file = Some(file);
+        Ok(history)
+    }
+
+    fn push(&mut self, entry: HistoryEntry<T>) {
+        self.entries
+            .entry(entry.document.primary_key())
+            .or_default()
+            .push(entry);
+    }
+
+    fn append(&mut self, entries: Vec<HistoryEntry<T>>) -> Result<(), Error> {
+        if let Some(file) = self.file.as_mut() {
+            let mut lines = String::new();
+            for entry in entries.iter() {
+                lines.push_str(&serde_json::to_string(entry)?);
+                lines.push('\n');
+            }
+            file.write_all(lines.as_bytes())?;
+        }
+        for entry in entries {
+            self.push(entry);
+        }
+        Ok(())
+    }
+
+    fn entries(&self, pk: &T::Key) -> &[HistoryEntry<T>] {
+        self.entries.get(pk).map_or(&[], |entries| entries)
+    }
+
+    pub(super) fn flush(&mut self) -> Result<(), Error> {
+        if let Some(file) = self.file.as_mut() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Keep the prior version of a document on every update and delete.
+    /// The history is stored alongside the file and dir backends and
+    /// kept in memory for other backends.
+    pub fn enable_history(&mut self) -> Result<(), Error> {
+        if self.history.is_none() {
+            self.history = Some(History::open(self.backend.history_path())?);
+        }
+        Ok(())
+    }
+
+    /// The prior versions of a document, oldest first. The current
+    /// version is not included.
+    pub fn history(&self, pk: &T::Key) -> Result<Vec<HistoryEntry<T>>, Error> {
+        Ok(self.history_store()?.entries(pk).to_vec())
+    }
+
+    /// The document as it was at `at`, or `None` if it did not exist then.
+    pub fn as_of(&self, pk: &T::Key, at: DateTime<Utc>) -> Result<Option<T>, Error> {
+        let history = self.history_store()?;
+        if let (Some(doc), Some(meta)) = (self.documents.get(pk), self.meta.get(pk)) {
+            if meta.written_at.is_none_or(|written_at| written_at <= at) {
+                return Ok(meta.deleted_at.is_none().then(|| doc.clone()));
+            }
+        }
+        Ok(history
+            .entries(pk)
+            .iter()
+            .rev()
+            .find(|entry| entry.current_at(at))
+            .map(|entry| entry.document.clone()))
+    }
+
+    /// Write the version of a document with `revision` back as its
+    /// latest version, undeleting it if needed. Returns the new revision.
+    pub fn restore(&mut self, pk: &T::Key, revision: u64) -> Result<u64, Error> {
+        let doc = self
+            .history_store()?
+            .entries(pk)
+            .iter()
+            .find(|entry| entry.revision == revision)
+            .map(|entry| entry.document.clone())
+            .ok_or(Error::NotFound)?;
+        self.upsert(doc)?;
+        self.revision(pk).ok_or(Error::NotFound)
+    }
+
+    /// The last revision of a deleted document recorded in the history.
+    pub(super) fn last_revision(&self, pk: &T::Key) -> Option<u64> {
+        let entries = self.history.as_ref()?.entries(pk);
+        entries.iter().map(|entry| entry.revision).max()
+    }
+
+    /// Record the versions replaced by applied writes.
+    pub(super) fn record_history(&mut self, changes: &[Change<T>]) -> Result<(), Error> {
+        let Some(history) = self.history.as_mut() else {
+            return Ok(());
+        };
+        let replaced_at = Utc::now();
+        let entries = changes
+            .iter()
+            .filter_map(|change| {
+                let (old, meta) = change.old.as_ref()?;
+                Some(HistoryEntry {
+                    revision: meta.revision,
+                    written_at: meta.written_at,
+                    replaced_at,
+                    deleted: change.deleted,
+                    document: old.clone(),
+                })
+            })
+            .collect();
+        history.append(entries)
+    }
+
+    fn history_store(&self) -> Result<&History<T>, Error> {
+        self.history.as_ref().ok_or(Error::HistoryDisabled)
+    }
+}
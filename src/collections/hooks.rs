@@ -0,0 +1,168 @@
+use std::fmt::Debug;
+
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{ChangeEvent, Document, Error};
+
+use super::collection::Collection;
+use super::wal::WalOp;
+
+type BeforeInsert<T> = Box<dyn Fn(&mut T) -> Result<(), Error> + Send + Sync>;
+type BeforeUpdate<T> = Box<dyn Fn(&T, &mut T) -> Result<(), Error> + Send + Sync>;
+type BeforeDelete<T> = Box<dyn Fn(&T) -> Result<(), Error> + Send + Sync>;
+type AfterWrite<T> = Box<dyn Fn(&T) + Send + Sync>;
+type AfterUpdate<T> = Box<dyn Fn(&T, &T) + Send + Sync>;
+
+/// Functions run by the collection around its writes.
+pub(super) struct Hooks<T> {
+    before_insert: Vec<BeforeInsert<T>>,
+    before_update: Vec<BeforeUpdate<T>>,
+    before_delete: Vec<BeforeDelete<T>>,
+    after_insert: Vec<AfterWrite<T>>,
+    after_update: Vec<AfterUpdate<T>>,
+    after_delete: Vec<AfterWrite<T>>,
+}
+
+impl<T> Default for Hooks<T> {
+    fn default() -> Self {
+        Hooks {
+            before_insert: vec![],
+            before_update: vec![],
+            before_delete: vec![],
+            after_insert: vec![],
+            after_update: vec![],
+            after_delete: vec![],
+        }
+    }
+}
+
+impl<T> Hooks<T> {
+    /// Whether any hook needs to hear about applied writes.
+    pub(super) fn has_after(&self) -> bool {
+        !self.after_insert.is_empty()
+            || !self.after_update.is_empty()
+            || !self.after_delete.is_empty()
+    }
+}
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Run `f` on each new document before it is checked and inserted.
+    /// `f` may change the document, other than its primary key,
+    /// or return an error to stop the insert.
+    pub fn before_insert(
+        &mut self,
+        f: impl Fn(&mut T) -> Result<(), Error> + Send + Sync + 'static,
+    ) {
+        self.hooks.before_insert.push(Box::new(f));
+    }
+
+    /// Run `f` with the current and new versions of a document before
+    /// an update is checked and applied. `f` may change the new version,
+    /// other than its primary key, or return an error to stop the update.
+    /// Updates of documents that do not exist run the insert hooks.
+    pub fn before_update(
+        &mut self,
+        f: impl Fn(&T, &mut T) -> Result<(), Error> + Send + Sync + 'static,
+    ) {
+        self.hooks.before_update.push(Box::new(f));
+    }
+
+    /// Run `f` on a document before it is deleted.
+    /// Returning an error stops the delete.
+    pub fn before_delete(&mut self, f: impl Fn(&T) -> Result<(), Error> + Send + Sync + 'static) {
+        self.hooks.before_delete.push(Box::new(f));
+    }
+
+    /// Run `f` on each document once it has been inserted.
+    pub fn after_insert(&mut self, f: impl Fn(&T) + Send + Sync + 'static) {
+        self.hooks.after_insert.push(Box::new(f));
+    }
+
+    /// Run `f` with the old and new versions of a document once it has been updated.
+    pub fn after_update(&mut self, f: impl Fn(&T, &T) + Send + Sync + 'static) {
+        self.hooks.after_update.push(Box::new(f));
+    }
+
+    /// Run `f` on each document once it has been deleted.
+    pub fn after_delete(&mut self, f: impl Fn(&T) + Send + Sync + 'static) {
+        self.hooks.after_delete.push(Box::new(f));
+    }
+
+    /// Run the before hooks for a write, returning the write to make.
+    pub(super) fn run_before_hooks(&self, op: WalOp<T>) -> Result<WalOp<T>, Error> {
+        Ok(match op {
+            WalOp::Insert(doc) => WalOp::Insert(self.before_write(doc, true)?),
+            WalOp::Update(doc) => WalOp::Update(self.before_write(doc, false)?),
+            WalOp::Delete(pk) => {
+                self.before_remove(&pk)?;
+                WalOp::Delete(pk)
+            }
+            WalOp::SoftDelete(pk) => {
+                self.before_remove(&pk)?;
+                WalOp::SoftDelete(pk)
+            }
+        })
+    }
+
+    /// Run the insert or update hooks on a document about to be written.
+    /// Writing over an expired document counts as an insert.
+    pub(super) fn before_write(&self, mut doc: T, insert: bool) -> Result<T, Error> {
+        let pk = doc.primary_key();
+        let now = Utc::now();
+        let old = self
+            .documents
+            .get(&pk)
+            .filter(|old| !self.expired_at(old, self.meta.get(&pk), now));
+        match old {
+            Some(old) if !insert => {
+                for hook in self.hooks.before_update.iter() {
+                    hook(old, &mut doc)?;
+                }
+            }
+            _ => {
+                for hook in self.hooks.before_insert.iter() {
+                    hook(&mut doc)?;
+                }
+            }
+        }
+        if doc.primary_key() != pk {
+            return Err(Error::Conflict {
+                reason: "a hook changed the primary key".to_string(),
+            });
+        }
+        Ok(doc)
+    }
+
+    /// Run the delete hooks on a document about to be deleted.
+    pub(super) fn before_remove(&self, pk: &T::Key) -> Result<(), Error> {
+        if let Some(old) = self.documents.get(pk) {
+            for hook in self.hooks.before_delete.iter() {
+                hook(old)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the after hooks for writes that have been applied.
+    pub(super) fn run_after_hooks(&self, events: &[ChangeEvent<T>]) {
+        for event in events {
+            match event {
+                ChangeEvent::Inserted { new } => {
+                    self.hooks.after_insert.iter().for_each(|hook| hook(new))
+                }
+                ChangeEvent::Updated { old, new } => self
+                    .hooks
+                    .after_update
+                    .iter()
+                    .for_each(|hook| hook(old, new)),
+                ChangeEvent::Deleted { old } => {
+                    self.hooks.after_delete.iter().for_each(|hook| hook(old))
+                }
+            }
+        }
+    }
+}
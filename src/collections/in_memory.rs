@@ -0,0 +1,36 @@
+use crate::{Document, DocumentMeta, Error};
+
+use super::backend::StorageBackend;
+
+/// A backend that does not persist anything.
+/// The data is lost when the collection is dropped.
+#[derive(Debug, Default)]
+pub struct InMemoryBackend;
+
+impl InMemoryBackend {
+    pub fn new() -> Self {
+        InMemoryBackend
+    }
+}
+
+impl<T: Document<T>> StorageBackend<T> for InMemoryBackend {
+    fn load_all(&mut self) -> Result<Vec<(T, DocumentMeta)>, Error> {
+        Ok(vec![])
+    }
+
+    fn persist_insert(&mut self, _doc: &T, _meta: &DocumentMeta) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn persist_update(&mut self, _doc: &T, _meta: &DocumentMeta) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn persist_delete(&mut self, _pk: &T::Key) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::ops::RangeBounds;
+
+use chrono::Utc;
+use indexmap::IndexSet;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{Document, Error};
+
+use super::collection::Collection;
+
+/// Maps each value declared by `Document::unique_keys`
+/// to the document that holds it.
+#[derive(Debug)]
+pub struct UniqueIndex<K> {
+    keys: HashMap<(&'static str, String), K>,
+}
+
+impl<K> Default for UniqueIndex<K> {
+    fn default() -> Self {
+        UniqueIndex {
+            keys: HashMap::new(),
+        }
+    }
+}
+
+impl<K> UniqueIndex<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// The document holding `value` for the unique key `name`.
+    pub fn owner(&self, name: &'static str, value: &Value) -> Option<&K> {
+        self.keys.get(&(name, value.to_string()))
+    }
+
+    /// Error if another document already holds one of the unique keys of `doc`.
+    pub fn check<T: Document<T, Key = K>>(&self, doc: &T) -> Result<(), Error> {
+        let pk = doc.primary_key();
+        for (name, value) in doc.unique_keys() {
+            if self.owner(name, &value).is_some_and(|owner| *owner != pk) {
+                return Err(unique_violation(name, &value));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert<T: Document<T, Key = K>>(&mut self, doc: &T) {
+        let pk = doc.primary_key();
+        for (name, value) in doc.unique_keys() {
+            self.keys.insert((name, value.to_string()), pk.clone());
+        }
+    }
+
+    pub fn remove<T: Document<T, Key = K>>(&mut self, doc: &T) {
+        let pk = doc.primary_key();
+        for (name, value) in doc.unique_keys() {
+            let key = (name, value.to_string());
+            if self.keys.get(&key) == Some(&pk) {
+                self.keys.remove(&key);
+            }
+        }
+    }
+}
+
+pub(super) fn unique_violation(name: &'static str, value: &Value) -> Error {
+    Error::UniqueViolation {
+        key: name,
+        value: value.to_string(),
+    }
+}
+
+/// An index over a key derived from each document, kept up to
+/// date by the collection as documents are written.
+pub trait SecondaryIndex<T: Document<T>>: Send + Sync {
+    fn insert(&mut self, doc: &T);
+    fn remove(&mut self, doc: &T);
+    /// Primary keys of the documents with `key`,
+    /// or `None` if `key` is not of the index's key type.
+    fn lookup(&self, key: &dyn Any) -> Option<Box<dyn Iterator<Item = &T::Key> + '_>>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+type KeyFn<T, K> = Box<dyn Fn(&T) -> K + Send + Sync>;
+
+/// Looks up documents by a key that is hashed.
+pub struct HashIndex<T: Document<T>, K> {
+    key: KeyFn<T, K>,
+    entries: HashMap<K, IndexSet<T::Key>>,
+}
+
+impl<T, K> HashIndex<T, K>
+where
+    T: Document<T>,
+    K: Hash + Eq,
+{
+    /// Primary keys of the documents with this key.
+    pub fn get(&self, key: &K) -> impl Iterator<Item = &T::Key> {
+        self.entries.get(key).into_iter().flatten()
+    }
+}
+
+impl<T, K> SecondaryIndex<T> for HashIndex<T, K>
+where
+    T: Document<T> + 'static,
+    K: Hash + Eq + Send + Sync + 'static,
+{
+    fn insert(&mut self, doc: &T) {
+        let key = (self.key)(doc);
+        self.entries
+            .entry(key)
+            .or_default()
+            .insert(doc.primary_key());
+    }
+
+    fn remove(&mut self, doc: &T) {
+        let key = (self.key)(doc);
+        if let Some(pks) = self.entries.get_mut(&key) {
+            pks.shift_remove(&doc.primary_key());
+            if pks.is_empty() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    fn lookup(&self, key: &dyn Any) -> Option<Box<dyn Iterator<Item = &T::Key> + '_>> {
+        let key = key.downcast_ref::<K>()?;
+        Some(Box::new(self.get(key)))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Looks up documents by an ordered key, supporting range queries.
+pub struct BTreeIndex<T: Document<T>, K> {
+    key: KeyFn<T, K>,
+    entries: BTreeMap<K, IndexSet<T::Key>>,
+}
+
+impl<T, K> BTreeIndex<T, K>
+where
+    T: Document<T>,
+    K: Ord,
+{
+    /// Primary keys of the documents with this key.
+    pub fn get(&self, key: &K) -> impl Iterator<Item = &T::Key> {
+        self.entries.get(key).into_iter().flatten()
+    }
+
+    /// Primary keys of the documents with a key in `range`, in key order.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl DoubleEndedIterator<Item = &T::Key> {
+        self.entries.range(range).flat_map(|(_, pks)| pks)
+    }
+}
+
+impl<T, K> SecondaryIndex<T> for BTreeIndex<T, K>
+where
+    T: Document<T> + 'static,
+    K: Ord + Send + Sync + 'static,
+{
+    fn insert(&mut self, doc: &T) {
+        let key = (self.key)(doc);
+        self.entries
+            .entry(key)
+            .or_default()
+            .insert(doc.primary_key());
+    }
+
+    fn remove(&mut self, doc: &T) {
+        let key = (self.key)(doc);
+        if let Some(pks) = self.entries.get_mut(&key) {
+            pks.shift_remove(&doc.primary_key());
+            if pks.is_empty() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    fn lookup(&self, key: &dyn Any) -> Option<Box<dyn Iterator<Item = &T::Key> + '_>> {
+        let key = key.downcast_ref::<K>()?;
+        Some(Box::new(self.get(key)))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Create a hash index named `name` over the key returned by `key`.
+    /// Replaces any index with the same name.
+    pub fn create_index<K>(&mut self, name: &str, key: impl Fn(&T) -> K + Send + Sync + 'static)
+    where
+        K: Hash + Eq + Send + Sync + 'static,
+    {
+        let index = HashIndex {
+            key: Box::new(key),
+            entries: HashMap::new(),
+        };
+        self.add_index(name, Box::new(index));
+    }
+
+    /// Create an ordered index named `name` over the key returned by `key`.
+    /// Ordered indexes also support `range_index`.
+    pub fn create_btree_index<K>(
+        &mut self,
+        name: &str,
+        key: impl Fn(&T) -> K + Send + Sync + 'static,
+    ) where
+        K: Ord + Send + Sync + 'static,
+    {
+        let index = BTreeIndex {
+            key: Box::new(key),
+            entries: BTreeMap::new(),
+        };
+        self.add_index(name, Box::new(index));
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> Result<(), Error> {
+        self.indexes
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| Error::IndexNotFound {
+                name: name.to_string(),
+            })
+    }
+
+    /// Find all documents whose key in the named index equals `key`.
+    /// `key` must be of the type returned by the index's key function.
+    pub fn by_index<K: 'static>(&self, name: &str, key: &K) -> Result<Vec<T>, Error> {
+        let pks = self.lookup_index(name, key)?;
+        Ok(self.documents_for(pks))
+    }
+
+    /// Find all documents whose key in the named ordered index
+    /// falls within `range`, in key order.
+    pub fn range_index<K, R>(&self, name: &str, range: R) -> Result<Vec<T>, Error>
+    where
+        K: Ord + Send + Sync + 'static,
+        R: RangeBounds<K>,
+    {
+        let pks = self.btree_index::<K>(name)?.range(range);
+        Ok(self.documents_for(pks))
+    }
+
+    pub(super) fn lookup_index<K: 'static>(
+        &self,
+        name: &str,
+        key: &K,
+    ) -> Result<Box<dyn Iterator<Item = &T::Key> + '_>, Error> {
+        self.indexes
+            .get(name)
+            .ok_or_else(|| Error::IndexNotFound {
+                name: name.to_string(),
+            })?
+            .lookup(key)
+            .ok_or_else(|| Error::TypeMismatch {
+                name: name.to_string(),
+            })
+    }
+
+    pub(super) fn btree_index<K>(&self, name: &str) -> Result<&BTreeIndex<T, K>, Error>
+    where
+        K: Ord + Send + Sync + 'static,
+    {
+        self.indexes
+            .get(name)
+            .ok_or_else(|| Error::IndexNotFound {
+                name: name.to_string(),
+            })?
+            .as_any()
+            .downcast_ref::<BTreeIndex<T, K>>()
+            .ok_or_else(|| Error::TypeMismatch {
+                name: name.to_string(),
+            })
+    }
+
+    pub(super) fn documents_for<'a>(&self, pks: impl IntoIterator<Item = &'a T::Key>) -> Vec<T> {
+        let now = Utc::now();
+        pks.into_iter()
+            .filter_map(|pk| Some((pk, self.documents.get(pk)?)))
+            .filter(|(pk, doc)| self.visible_at(pk, doc, now))
+            .map(|(_, doc)| doc.clone())
+            .collect()
+    }
+
+    fn add_index(&mut self, name: &str, mut index: Box<dyn SecondaryIndex<T>>) {
+        for doc in self.documents.values() {
+            index.insert(doc);
+        }
+        self.indexes.insert(name.to_string(), index);
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+use crate::{Document, Error};
+
+use super::collection::Collection;
+
+/// Produces the primary keys of documents inserted with `Collection::insert_new`.
+pub trait KeyGenerator<K>: Send + Sync {
+    fn next_key(&mut self) -> K;
+    /// The counter to persist with the collection after a key
+    /// is issued. Only generators that count return one.
+    fn counter(&self) -> Option<u64> {
+        None
+    }
+    /// Resume from a counter persisted by an earlier `counter` call.
+    fn restore(&mut self, _counter: u64) {}
+}
+
+/// Monotonic `u64` keys starting at 1. The last key issued is
+/// persisted by the backend so keys are not reused after a restart,
+/// even if the documents holding them were deleted.
+#[derive(Debug, Default)]
+pub struct Sequence {
+    last: u64,
+}
+
+impl KeyGenerator<u64> for Sequence {
+    fn next_key(&mut self) -> u64 {
+        self.last += 1;
+        self.last
+    }
+
+    fn counter(&self) -> Option<u64> {
+        Some(self.last)
+    }
+
+    fn restore(&mut self, counter: u64) {
+        self.last = self.last.max(counter);
+    }
+}
+
+/// Random UUIDs.
+#[derive(Debug, Default)]
+pub struct UuidV4;
+
+impl KeyGenerator<Uuid> for UuidV4 {
+    fn next_key(&mut self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// Time-ordered UUIDs, so documents sort by when they were created.
+#[derive(Debug, Default)]
+pub struct UuidV7;
+
+impl KeyGenerator<Uuid> for UuidV7 {
+    fn next_key(&mut self) -> Uuid {
+        Uuid::now_v7()
+    }
+}
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// ULIDs as 26 character strings: a millisecond timestamp followed by
+/// random bits. Keys made in the same millisecond increase monotonically.
+#[derive(Debug, Default)]
+pub struct Ulid {
+    last: u128,
+}
+
+impl KeyGenerator<String> for Ulid {
+    fn next_key(&mut self) -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let random = Uuid::new_v4().as_u128() & ((1 << 80) - 1);
+        let mut value = ((millis & ((1 << 48) - 1)) << 80) | random;
+        if value <= self.last {
+            value = self.last + 1;
+        }
+        self.last = value;
+        (0..26)
+            .rev()
+            .map(|i| CROCKFORD[((value >> (i * 5)) & 31) as usize] as char)
+            .collect()
+    }
+}
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Use `generator` to assign the keys of documents inserted with
+    /// `insert_new`, resuming any counter persisted by the backend.
+    pub fn set_key_generator(
+        &mut self,
+        mut generator: impl KeyGenerator<T::Key> + 'static,
+    ) -> Result<(), Error> {
+        if let Some(counter) = self.backend.load_counter()? {
+            generator.restore(counter);
+        }
+        self.key_generator = Some(Box::new(generator));
+        Ok(())
+    }
+
+    /// Insert the document built by `f` from the next generated key,
+    /// returning the key. Keys already in use are skipped.
+    pub fn insert_new(&mut self, f: impl FnOnce(T::Key) -> T) -> Result<T::Key, Error> {
+        let generator = self
+            .key_generator
+            .as_mut()
+            .ok_or(Error::MissingKeyGenerator)?;
+        let mut key = generator.next_key();
+        while self.documents.contains_key(&key) {
+            key = generator.next_key();
+        }
+        if let Some(counter) = generator.counter() {
+            self.backend.persist_counter(counter)?;
+        }
+
+        let doc = f(key.clone());
+        if doc.primary_key() != key {
+            return Err(Error::Conflict {
+                reason: format!("document does not use the generated key {}", key),
+            });
+        }
+        self.insert(doc)?;
+        Ok(key)
+    }
+}
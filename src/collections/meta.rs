@@ -0,0 +1,54 @@
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Error;
+
+/// What the collection records about a document alongside it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentMeta {
+    /// Starts at 1 when the document is inserted and is incremented
+    /// on every update. Documents persisted before revisions were
+    /// recorded are loaded with revision 0.
+    pub revision: u64,
+    /// When this revision was written. `None` for documents
+    /// persisted before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub written_at: Option<DateTime<Utc>>,
+    /// When the document was soft deleted. Soft deleted documents
+    /// are hidden from reads until they are undeleted or purged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// The key that marks a document written with its metadata.
+const META: &str = "$meta";
+
+/// A document as the built-in backends write it to disk.
+#[derive(Serialize)]
+struct StoredRef<'a, T> {
+    #[serde(rename = "$meta")]
+    meta: &'a DocumentMeta,
+    doc: &'a T,
+}
+
+/// Serialize a document with its metadata.
+pub(crate) fn to_json<T: Serialize>(doc: &T, meta: &DocumentMeta) -> Result<String, Error> {
+    Ok(serde_json::to_string(&StoredRef { meta, doc })?)
+}
+
+/// Deserialize a document written by `to_json`, or a plain document
+/// written before the metadata was recorded.
+pub(crate) fn from_json<T: DeserializeOwned>(json: &str) -> Result<(T, DocumentMeta), Error> {
+    let mut value: Value = serde_json::from_str(json)?;
+    let Some(meta) = value.as_object_mut().and_then(|stored| stored.remove(META)) else {
+        return Ok((serde_json::from_value(value)?, DocumentMeta::default()));
+    };
+    let doc = value
+        .as_object_mut()
+        .and_then(|stored| stored.remove("doc"))
+        .ok_or_else(|| Error::InvalidFile {
+            reason: "document missing from stored metadata".to_string(),
+        })?;
+    Ok((serde_json::from_value(doc)?, serde_json::from_value(meta)?))
+}
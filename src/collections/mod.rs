@@ -1,7 +1,18 @@
-//pub mod dir_based_collection;
-//pub mod file_based_collection;
-//pub mod in_memory_collection;
-
+pub mod backend;
+pub mod batch;
+pub mod changes;
 pub mod collection;
 pub mod dir_based;
+pub mod expiry;
 pub mod file_based;
+pub mod guard;
+pub mod history;
+pub mod hooks;
+pub mod in_memory;
+pub mod index;
+pub mod keys;
+pub mod meta;
+pub mod query;
+pub mod soft_delete;
+pub mod time_index;
+pub mod wal;
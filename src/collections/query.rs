@@ -0,0 +1,156 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::ops::RangeBounds;
+
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Document, Error};
+
+use super::collection::Collection;
+
+type Predicate<'a, T> = Box<dyn Fn(&T) -> bool + 'a>;
+type Comparator<'a, T> = Box<dyn Fn(&T, &T) -> Ordering + 'a>;
+
+/// A query over a collection built up from filters, a sort order and
+/// a page. Nothing is evaluated until `collect`, `count`, `exists` or
+/// `first` is called, and only the documents returned are cloned.
+pub struct Query<'a, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    collection: &'a Collection<T>,
+    /// Candidates found through an index. `None` scans every document.
+    candidates: Option<Vec<&'a T::Key>>,
+    filters: Vec<Predicate<'a, T>>,
+    sort: Option<Comparator<'a, T>>,
+    skip: usize,
+    limit: Option<usize>,
+    with_deleted: bool,
+}
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Start a query over the collection.
+    pub fn query(&self) -> Query<'_, T> {
+        Query {
+            collection: self,
+            candidates: None,
+            filters: vec![],
+            sort: None,
+            skip: 0,
+            limit: None,
+            with_deleted: false,
+        }
+    }
+}
+
+impl<'a, T> Query<'a, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Only consider documents whose key in the named index equals `key`.
+    pub fn where_index<K: 'static>(mut self, name: &str, key: &K) -> Result<Self, Error> {
+        let pks = self.collection.lookup_index(name, key)?;
+        self.candidates = Some(pks.collect());
+        Ok(self)
+    }
+
+    /// Only consider documents whose key in the named ordered
+    /// index falls within `range`. Results are in key order
+    /// unless a sort is given.
+    pub fn where_range<K, R>(mut self, name: &str, range: R) -> Result<Self, Error>
+    where
+        K: Ord + Send + Sync + 'static,
+        R: RangeBounds<K>,
+    {
+        let pks = self.collection.btree_index::<K>(name)?.range(range);
+        self.candidates = Some(pks.collect());
+        Ok(self)
+    }
+
+    /// Only return documents that satisfy `f`. Filters are combined.
+    pub fn filter(mut self, f: impl Fn(&T) -> bool + 'a) -> Self {
+        self.filters.push(Box::new(f));
+        self
+    }
+
+    /// Sort the documents by the key returned by `f`.
+    pub fn sort_by_key<K: Ord>(mut self, f: impl Fn(&T) -> K + 'a) -> Self {
+        self.sort = Some(Box::new(move |a, b| f(a).cmp(&f(b))));
+        self
+    }
+
+    /// Sort the documents using the comparator `f`.
+    pub fn sort_by(mut self, f: impl Fn(&T, &T) -> Ordering + 'a) -> Self {
+        self.sort = Some(Box::new(f));
+        self
+    }
+
+    /// Include soft deleted documents.
+    pub fn with_deleted(mut self) -> Self {
+        self.with_deleted = true;
+        self
+    }
+
+    /// Skip the first `n` matching documents.
+    pub fn skip(mut self, n: usize) -> Self {
+        self.skip = n;
+        self
+    }
+
+    /// Return at most `n` documents.
+    pub fn limit(mut self, n: usize) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    /// The documents on the requested page.
+    pub fn collect(self) -> Vec<T> {
+        self.run().cloned().collect()
+    }
+
+    /// The number of documents `collect` would return.
+    pub fn count(self) -> usize {
+        self.run().count()
+    }
+
+    /// Whether any document matches.
+    pub fn exists(self) -> bool {
+        self.run().next().is_some()
+    }
+
+    /// The first document on the requested page.
+    pub fn first(self) -> Option<T> {
+        self.run().next().cloned()
+    }
+
+    fn run(self) -> impl Iterator<Item = &'a T> {
+        let collection = self.collection;
+        let with_deleted = self.with_deleted;
+        let pks: Box<dyn Iterator<Item = &'a T::Key> + 'a> = match self.candidates {
+            Some(pks) => Box::new(pks.into_iter()),
+            None => Box::new(collection.documents.keys()),
+        };
+        let now = Utc::now();
+        let candidates = pks
+            .filter_map(move |pk| Some((pk, collection.documents.get(pk)?)))
+            .filter(move |(pk, doc)| with_deleted || collection.visible_at(pk, doc, now))
+            .map(|(_, doc)| doc);
+        let filters = self.filters;
+        let matches = candidates.filter(move |doc| filters.iter().all(|f| f(doc)));
+        let sorted: Box<dyn Iterator<Item = &'a T> + 'a> = match self.sort {
+            Some(sort) => {
+                let mut docs: Vec<&T> = matches.collect();
+                docs.sort_by(|a, b| sort(a, b));
+                Box::new(docs.into_iter())
+            }
+            None => Box::new(matches),
+        };
+        sorted
+            .skip(self.skip)
+            .take(self.limit.unwrap_or(usize::MAX))
+    }
+}
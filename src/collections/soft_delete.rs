@@ -0,0 +1,117 @@
+use std::fmt::Debug;
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Document, Error};
+
+use super::collection::Collection;
+use super::wal::WalOp;
+
+/// A view of a collection that includes soft deleted documents.
+pub struct WithDeleted<'a, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    collection: &'a Collection<T>,
+}
+
+impl<'a, T> WithDeleted<'a, T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Iterate over every document, including soft deleted ones.
+    pub fn iter(&self) -> impl Iterator<Item = &'a T> {
+        self.collection.documents.values()
+    }
+
+    /// Find all documents that meet the criteria.
+    pub fn filter(&self, f: impl Fn(&T) -> bool) -> Vec<T> {
+        self.iter().filter(|doc| f(doc)).cloned().collect()
+    }
+
+    /// Find the first document that satisfies the criteria.
+    pub fn find(&self, f: impl Fn(&T) -> bool) -> Option<T> {
+        self.iter().find(|doc| f(doc)).cloned()
+    }
+
+    /// Get a document by its primary key
+    pub fn by_primary_key(&self, pk: &T::Key) -> Option<T> {
+        self.collection.documents.get(pk).cloned()
+    }
+
+    /// When a document was soft deleted, or `None` if it is not deleted.
+    pub fn deleted_at(&self, pk: &T::Key) -> Option<DateTime<Utc>> {
+        self.collection.meta.get(pk)?.deleted_at
+    }
+}
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Make `delete` mark documents deleted instead of removing them.
+    /// Soft deleted documents are hidden from reads, keep their
+    /// primary and unique keys, and are removed by `purge`.
+    pub fn enable_soft_delete(&mut self) {
+        self.soft_delete = true;
+    }
+
+    /// Read documents including the soft deleted ones.
+    pub fn with_deleted(&self) -> WithDeleted<'_, T> {
+        WithDeleted { collection: self }
+    }
+
+    /// Restore a soft deleted document.
+    pub fn undelete(&mut self, pk: &T::Key) -> Result<(), Error> {
+        if self
+            .meta
+            .get(pk)
+            .is_none_or(|meta| meta.deleted_at.is_none())
+        {
+            return Err(Error::NotFound);
+        }
+        let doc = self.documents.get(pk).ok_or(Error::NotFound)?.clone();
+        self.commit(vec![WalOp::Update(doc)])
+    }
+
+    /// Permanently remove the documents soft deleted before `older_than`.
+    /// Returns the number of documents removed.
+    pub fn purge(&mut self, older_than: DateTime<Utc>) -> Result<usize, Error> {
+        let ops: Vec<WalOp<T>> = self
+            .meta
+            .iter()
+            .filter(|(_, meta)| meta.deleted_at.is_some_and(|at| at < older_than))
+            .map(|(pk, _)| WalOp::Delete(pk.clone()))
+            .collect();
+        let purged = ops.len();
+        if purged > 0 {
+            self.commit(ops)?;
+        }
+        Ok(purged)
+    }
+
+    /// Whether a document exists and is visible to reads, i.e., it is
+    /// neither soft deleted nor expired.
+    pub(super) fn is_visible(&self, pk: &T::Key) -> bool {
+        self.documents
+            .get(pk)
+            .is_some_and(|doc| self.visible_at(pk, doc, Utc::now()))
+    }
+
+    /// Whether `doc` is visible to a read made at `now`. Reads that
+    /// scan the collection take the time once and pass it to each check.
+    pub(super) fn visible_at(&self, pk: &T::Key, doc: &T, now: DateTime<Utc>) -> bool {
+        let meta = self.meta.get(pk);
+        meta.is_none_or(|meta| meta.deleted_at.is_none()) && !self.expired_at(doc, meta, now)
+    }
+
+    /// The write that deletes a document.
+    pub(super) fn delete_op(&self, pk: T::Key) -> WalOp<T> {
+        if self.soft_delete {
+            WalOp::SoftDelete(pk)
+        } else {
+            WalOp::Delete(pk)
+        }
+    }
+}
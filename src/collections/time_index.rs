@@ -0,0 +1,63 @@
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Document, Error};
+
+use super::collection::Collection;
+use super::index::BTreeIndex;
+
+/// An ordered index keyed by timestamp.
+pub type TimeIndex<T> = BTreeIndex<T, DateTime<Utc>>;
+
+impl<T> Collection<T>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    /// Create an ordered index named `name` over the timestamp returned by `key`.
+    pub fn create_time_index(
+        &mut self,
+        name: &str,
+        key: impl Fn(&T) -> DateTime<Utc> + Send + Sync + 'static,
+    ) {
+        self.create_btree_index(name, key);
+    }
+
+    /// Documents with a timestamp in `range`, oldest first.
+    pub fn time_range<R>(&self, name: &str, range: R) -> Result<Vec<T>, Error>
+    where
+        R: RangeBounds<DateTime<Utc>>,
+    {
+        self.range_index(name, range)
+    }
+
+    /// The `n` documents with the newest timestamps, newest first.
+    pub fn latest(&self, name: &str, n: usize) -> Result<Vec<T>, Error> {
+        let now = Utc::now();
+        Ok(self
+            .time_index(name)?
+            .range(..)
+            .rev()
+            .filter_map(|pk| Some((pk, self.documents.get(pk)?)))
+            .filter(|(pk, doc)| self.visible_at(pk, doc, now))
+            .take(n)
+            .map(|(_, doc)| doc.clone())
+            .collect())
+    }
+
+    /// Documents with a timestamp before `t`, oldest first.
+    pub fn before(&self, name: &str, t: DateTime<Utc>) -> Result<Vec<T>, Error> {
+        self.time_range(name, ..t)
+    }
+
+    /// Documents with a timestamp after `t`, oldest first.
+    pub fn after(&self, name: &str, t: DateTime<Utc>) -> Result<Vec<T>, Error> {
+        self.time_range(name, (Bound::Excluded(t), Bound::Unbounded))
+    }
+
+    fn time_index(&self, name: &str) -> Result<&TimeIndex<T>, Error> {
+        self.btree_index(name)
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::{Document, Error};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// A write recorded in the write-ahead log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WalOp<T: Document<T>> {
+    Insert(T),
+    Update(T),
+    Delete(T::Key),
+    /// Mark a document deleted, keeping it. See `Collection::enable_soft_delete`.
+    SoftDelete(T::Key),
+}
+
+/// An append-only log of writes that have not yet been applied to the backend.
+/// Each line holds the operations of one write, which are replayed
+/// together when the collection is next opened.
+#[derive(Debug)]
+pub struct Wal {
+    pub path: PathBuf,
+    file: File,
+}
+
+impl Wal {
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        Ok(Wal { path, file })
+    }
+
+    /// Append the operations of a write and sync them to disk.
+    pub fn append<T: Document<T> + Serialize>(&mut self, ops: &[WalOp<T>]) -> Result<(), Error> {
+        let mut json = serde_json::to_string(ops)?;
+        json.push('\n');
+        self.file.write_all(json.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Read the writes in the log. A final line that was
+    /// only partly written before a crash is ignored.
+    pub fn read_all<T: Document<T> + DeserializeOwned>(
+        &mut self,
+    ) -> Result<Vec<Vec<WalOp<T>>>, Error> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut entries = vec![];
+        for line in BufReader::new(&self.file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(ops) => entries.push(ops),
+                Err(_) => break,
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Empty the log once its writes have been applied.
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
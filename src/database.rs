@@ -0,0 +1,316 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    fs,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{Collection, CollectionBackend, CollectionOptions, Document, Error};
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    collections: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestEntry {
+    backend: CollectionBackend,
+}
+
+/// A collection whose document type has been erased so
+/// collections of different types can be held together.
+trait AnyCollection: Send + Sync {
+    fn sync(&self) -> Result<(), Error>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T> AnyCollection for Arc<RwLock<Collection<T>>>
+where
+    T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+{
+    fn sync(&self) -> Result<(), Error> {
+        self.write().map_err(|_| Error::LockPoisoned)?.sync()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A directory holding named collections of different document types.
+/// The collections are recorded in a `manifest.json` file in the directory.
+pub struct Database {
+    pub path: PathBuf,
+    manifest: Manifest,
+    collections: HashMap<String, Box<dyn AnyCollection>>,
+}
+
+impl Database {
+    /// Open the database in `path`, creating the directory if needed.
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        fs::create_dir_all(&path)?;
+        let manifest_path = path.join(MANIFEST);
+        let manifest = if manifest_path.exists() {
+            serde_json::from_reader(fs::File::open(&manifest_path)?)?
+        } else {
+            Manifest::default()
+        };
+        Ok(Database {
+            path,
+            manifest,
+            collections: HashMap::new(),
+        })
+    }
+
+    /// Open or create the named collection. New collections
+    /// are stored in a directory.
+    pub fn collection<T>(&mut self, name: &str) -> Result<Arc<RwLock<Collection<T>>>, Error>
+    where
+        T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+    {
+        self.collection_with_backend(name, CollectionBackend::Dir)
+    }
+
+    /// Open the named collection, creating it with `backend` if it does not exist.
+    /// An existing collection keeps the backend it was created with.
+    pub fn collection_with_backend<T>(
+        &mut self,
+        name: &str,
+        backend: CollectionBackend,
+    ) -> Result<Arc<RwLock<Collection<T>>>, Error>
+    where
+        T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+    {
+        self.collection_with_options(name, backend, CollectionOptions::default())
+    }
+
+    /// Open the named collection with `options`, such as a write-ahead log
+    /// or `Durability`, creating it with `backend` if it does not exist.
+    /// The options are not recorded and are ignored if the collection
+    /// is already open.
+    pub fn collection_with_options<T>(
+        &mut self,
+        name: &str,
+        backend: CollectionBackend,
+        options: CollectionOptions,
+    ) -> Result<Arc<RwLock<Collection<T>>>, Error>
+    where
+        T: Document<T> + Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Debug,
+    {
+        if let Some(open) = self.collections.get(name) {
+            return open
+                .as_any()
+                .downcast_ref::<Arc<RwLock<Collection<T>>>>()
+                .cloned()
+                .ok_or_else(|| Error::TypeMismatch {
+                    name: name.to_string(),
+                });
+        }
+
+        let entry = match self.manifest.collections.get(name) {
+            Some(entry) => entry.clone(),
+            None => {
+                Self::check_name(name)?;
+                ManifestEntry { backend }
+            }
+        };
+        let path = self.collection_path(name, entry.backend);
+        let collection = Arc::new(RwLock::new(Collection::open(entry.backend, path, options)?));
+        if !self.manifest.collections.contains_key(name) {
+            self.manifest.collections.insert(name.to_string(), entry);
+            self.write_manifest()?;
+        }
+        self.collections
+            .insert(name.to_string(), Box::new(collection.clone()));
+        Ok(collection)
+    }
+
+    /// Names of every collection in the database.
+    pub fn list_collections(&self) -> Vec<String> {
+        self.manifest.collections.keys().cloned().collect()
+    }
+
+    /// Remove a collection and delete its data. Handles to the collection
+    /// that are still held keep their documents in memory. Writes through
+    /// them fail with `Error::Io` for the dir backend and go to the deleted
+    /// file, and are lost, for the file backend.
+    pub fn drop_collection(&mut self, name: &str) -> Result<(), Error> {
+        let entry = self
+            .manifest
+            .collections
+            .remove(name)
+            .ok_or(Error::NotFound)?;
+        self.collections.remove(name);
+        self.write_manifest()?;
+        if let Some(path) = self.collection_path(name, entry.backend) {
+            match entry.backend {
+                CollectionBackend::Dir => fs::remove_dir_all(path)?,
+                _ => {
+                    fs::remove_file(&path)?;
+                    let mut history = path.into_os_string();
+                    history.push(".history");
+                    if fs::exists(&history)? {
+                        fs::remove_file(history)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync every open collection to disk.
+    pub fn flush(&self) -> Result<(), Error> {
+        for collection in self.collections.values() {
+            collection.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Sync every open collection and close the database.
+    pub fn close(self) -> Result<(), Error> {
+        self.flush()
+    }
+
+    fn collection_path(&self, name: &str, backend: CollectionBackend) -> Option<PathBuf> {
+        match backend {
+            CollectionBackend::Dir => Some(self.path.join(name)),
+            CollectionBackend::File => Some(self.path.join(format!("{}.col", name))),
+            CollectionBackend::InMemory => None,
+        }
+    }
+
+    /// Names become file names so are limited to a safe set of characters.
+    fn check_name(name: &str) -> Result<(), Error> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn write_manifest(&self) -> Result<(), Error> {
+        let path = self.path.join(MANIFEST);
+        let tmp_path = self.path.join(format!("{}.tmp", MANIFEST));
+        let mut f = fs::File::create(&tmp_path)?;
+        f.write_all(serde_json::to_string_pretty(&self.manifest)?.as_bytes())?;
+        f.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        fs::File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Durability;
+    use uuid::Uuid;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct User {
+        uuid: Uuid,
+        name: String,
+    }
+
+    impl Document<User> for User {
+        type Key = Uuid;
+
+        fn primary_key(&self) -> Uuid {
+            self.uuid
+        }
+
+        fn intersects(&self, _doc: &User) -> Result<(), &str> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Reading {
+        uuid: Uuid,
+        value: f64,
+    }
+
+    impl Document<Reading> for Reading {
+        type Key = Uuid;
+
+        fn primary_key(&self) -> Uuid {
+            self.uuid
+        }
+
+        fn intersects(&self, _doc: &Reading) -> Result<(), &str> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_database() {
+        let mut fp = std::env::current_dir().unwrap();
+        fp.push("collections");
+        fp.push("database");
+        let _ = fs::remove_dir_all(&fp);
+
+        let mut db = Database::open(fp.clone()).unwrap();
+        let users = db.collection::<User>("users").unwrap();
+        let readings = db
+            .collection_with_backend::<Reading>("readings", CollectionBackend::File)
+            .unwrap();
+        let user = User {
+            uuid: Uuid::new_v4(),
+            name: "bob".to_string(),
+        };
+        users.write().unwrap().insert(user.clone()).unwrap();
+        readings
+            .write()
+            .unwrap()
+            .insert(Reading {
+                uuid: Uuid::new_v4(),
+                value: 1.5,
+            })
+            .unwrap();
+
+        assert!(Arc::ptr_eq(
+            &users,
+            &db.collection::<User>("users").unwrap()
+        ));
+        assert!(matches!(
+            db.collection::<Reading>("users"),
+            Err(Error::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            db.collection::<User>("../users"),
+            Err(Error::InvalidName { .. })
+        ));
+        db.close().unwrap();
+
+        let mut db = Database::open(fp.clone()).unwrap();
+        assert_eq!(db.list_collections(), vec!["readings", "users"]);
+        let users = db.collection::<User>("users").unwrap();
+        assert!(users.read().unwrap().by_primary_key(&user.uuid).is_some());
+
+        db.drop_collection("readings").unwrap();
+        assert_eq!(db.list_collections(), vec!["users"]);
+        assert!(!fp.join("readings.col").exists());
+
+        let options = CollectionOptions {
+            wal_path: Some(fp.join("logged.wal")),
+            durability: Durability::Manual,
+        };
+        let logged = db
+            .collection_with_options::<User>("logged", CollectionBackend::Dir, options)
+            .unwrap();
+        logged.write().unwrap().insert(user).unwrap();
+        assert!(fp.join("logged.wal").exists());
+    }
+}
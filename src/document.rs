@@ -1,11 +1,49 @@
-use uuid::Uuid;
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
 
 /// Any struct that wants to be managed by a collection
 /// needs to satisfy these traits
 pub trait Document<T> {
+    /// Whether the collection calls `intersects` against every other
+    /// document on each write. Set to `false` when `unique_keys` covers
+    /// every check to avoid the O(n) scan.
+    const CHECK_INTERSECTS: bool = true;
+
+    /// The type of the primary key, e.g., `Uuid`, `u64` or `String`.
+    /// Composite keys can be a struct deriving the traits, with a
+    /// `Display` impl that is unique for each key.
+    type Key: Hash
+        + Eq
+        + Ord
+        + Clone
+        + Debug
+        + Display
+        + Serialize
+        + DeserializeOwned
+        + Send
+        + Sync
+        + 'static;
+
     // Returns the primary key for the document.
-    fn primary_key(&self) -> Uuid;
+    fn primary_key(&self) -> Self::Key;
     // Identifies whether is intersects with an existing document.
     // e.g., Can't have users with two emails.
-    fn intersects(&self, doc: &T) -> Result<(), &str>;
+    fn intersects(&self, _doc: &T) -> Result<(), &str> {
+        Ok(())
+    }
+    /// Named values that no two documents in the collection may share,
+    /// e.g., `vec![("email", json!(self.email))]`. Checked in O(1)
+    /// using an index maintained by the collection.
+    fn unique_keys(&self) -> Vec<(&'static str, Value)> {
+        vec![]
+    }
+    /// When the document expires. Expired documents are hidden from
+    /// reads and deleted by `Collection::sweep_expired`.
+    fn expires_at(&self) -> Option<DateTime<Utc>> {
+        None
+    }
 }
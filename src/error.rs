@@ -0,0 +1,102 @@
+use std::{error, fmt, io};
+
+use crate::ValidationErrors;
+
+/// Errors returned by collection operations.
+#[derive(Debug)]
+pub enum Error {
+    /// A document with the same primary key already exists.
+    DuplicateKey,
+    /// The document intersects with an existing document.
+    /// `reason` is the message returned by `Document::intersects`.
+    Conflict { reason: String },
+    /// Another document already holds this value for a unique key.
+    UniqueViolation { key: &'static str, value: String },
+    /// No document exists for the given primary key.
+    NotFound,
+    /// A serialised document does not fit within a row of the file.
+    RowTooLarge { length: usize, max_length: usize },
+    /// The collection needs a path for its backend but none was given.
+    MissingPath,
+    /// The name cannot be used for a collection.
+    InvalidName { name: String },
+    /// The named collection or index holds a different type to the one requested.
+    TypeMismatch { name: String },
+    /// No index with this name exists on the collection.
+    IndexNotFound { name: String },
+    /// The document was written by someone else since it was read.
+    StaleRevision { expected: u64, actual: u64 },
+    /// The document failed `Validate::validate`.
+    Invalid { errors: ValidationErrors },
+    /// A hook rejected the write.
+    Rejected { reason: String },
+    /// `insert_new` was called without a key generator being set.
+    MissingKeyGenerator,
+    /// History was read without `Collection::enable_history` being called.
+    HistoryDisabled,
+    /// A lock was poisoned by a thread that panicked while holding it.
+    LockPoisoned,
+    /// A file on disk is not in the format the backend expects.
+    InvalidFile { reason: String },
+    /// Reading from or writing to disk failed.
+    Io { source: io::Error },
+    /// A document could not be converted to or from JSON.
+    Serialize { source: serde_json::Error },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DuplicateKey => write!(f, "primary key already in use"),
+            Error::Conflict { reason } => write!(f, "document conflicts: {}", reason),
+            Error::UniqueViolation { key, value } => {
+                write!(f, "{} {} is already in use", key, value)
+            }
+            Error::NotFound => write!(f, "document not found"),
+            Error::RowTooLarge { length, max_length } => write!(
+                f,
+                "document of {} bytes exceeds the row length of {} bytes",
+                length, max_length
+            ),
+            Error::MissingPath => write!(f, "backend requires a path"),
+            Error::InvalidName { name } => write!(f, "invalid collection name {:?}", name),
+            Error::TypeMismatch { name } => write!(f, "{:?} holds a different type", name),
+            Error::IndexNotFound { name } => write!(f, "no index named {:?}", name),
+            Error::StaleRevision { expected, actual } => write!(
+                f,
+                "document is at revision {}, expected {}",
+                actual, expected
+            ),
+            Error::Invalid { errors } => write!(f, "invalid document: {}", errors),
+            Error::Rejected { reason } => write!(f, "write rejected: {}", reason),
+            Error::MissingKeyGenerator => write!(f, "collection has no key generator"),
+            Error::HistoryDisabled => write!(f, "collection does not keep history"),
+            Error::LockPoisoned => write!(f, "collection lock poisoned"),
+            Error::InvalidFile { reason } => write!(f, "invalid DB file: {}", reason),
+            Error::Io { .. } => write!(f, "error accessing the DB on disk"),
+            Error::Serialize { .. } => write!(f, "error converting document to or from JSON"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io { source } => Some(source),
+            Error::Serialize { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io { source }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(source: serde_json::Error) -> Self {
+        Error::Serialize { source }
+    }
+}
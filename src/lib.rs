@@ -1,5 +1,28 @@
 mod collections;
+mod database;
 mod document;
+mod error;
+mod validate;
 
-pub use crate::collections::collection::{Collection, CollectionBackend};
+pub use crate::collections::backend::{CompactionStats, StorageBackend};
+pub use crate::collections::batch::Batch;
+pub use crate::collections::changes::ChangeEvent;
+pub use crate::collections::collection::{
+    Collection, CollectionBackend, CollectionOptions, Durability,
+};
+pub use crate::collections::dir_based::DirBackend;
+pub use crate::collections::file_based::FileBackend;
+pub use crate::collections::guard::DocumentGuard;
+pub use crate::collections::history::HistoryEntry;
+pub use crate::collections::in_memory::InMemoryBackend;
+pub use crate::collections::index::{BTreeIndex, HashIndex, SecondaryIndex, UniqueIndex};
+pub use crate::collections::keys::{KeyGenerator, Sequence, Ulid, UuidV4, UuidV7};
+pub use crate::collections::meta::DocumentMeta;
+pub use crate::collections::query::Query;
+pub use crate::collections::soft_delete::WithDeleted;
+pub use crate::collections::time_index::TimeIndex;
+pub use crate::collections::wal::{Wal, WalOp};
+pub use crate::database::Database;
 pub use crate::document::Document;
+pub use crate::error::Error;
+pub use crate::validate::{Validate, ValidationErrors};
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Checks that a single document is valid on its own, e.g., that an
+/// email is well-formed or a reading is in range. Checks against other
+/// documents belong in `Document::intersects` or `Document::unique_keys`.
+///
+/// Call `Collection::enable_validation` to have the collection
+/// validate every document it writes.
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+/// The problems found with each field of a document.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationErrors {
+    pub fields: BTreeMap<String, Vec<String>>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        ValidationErrors::default()
+    }
+
+    /// Record a problem with `field`.
+    pub fn add(&mut self, field: &str, message: impl Into<String>) {
+        self.fields
+            .entry(field.to_string())
+            .or_default()
+            .push(message.into());
+    }
+
+    /// The problems recorded for `field`.
+    pub fn field(&self, field: &str) -> &[String] {
+        self.fields.get(field).map_or(&[], |messages| messages)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// `Ok` if no problems were recorded, for returning from `validate`.
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (field, messages) in self.fields.iter() {
+            for message in messages {
+                if !first {
+                    write!(f, "; ")?;
+                }
+                write!(f, "{}: {}", field, message)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}